@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS payment_outbox;
+DROP TABLE IF EXISTS payment_attempts;
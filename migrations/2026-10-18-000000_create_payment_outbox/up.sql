@@ -0,0 +1,24 @@
+CREATE TABLE IF NOT EXISTS payment_attempts (
+  id BIGSERIAL PRIMARY KEY,
+  message_key VARCHAR NOT NULL,
+  ws_session_id VARCHAR NOT NULL,
+  buyer_uid VARCHAR NOT NULL,
+  event_id VARCHAR NOT NULL,
+  -- nonce followed by the AES-256-GCM-SIV ciphertext of the Stripe client secret
+  client_secret BYTEA,
+  error VARCHAR,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS payment_outbox (
+  id BIGSERIAL PRIMARY KEY,
+  payment_attempt_id BIGINT NOT NULL REFERENCES payment_attempts(id),
+  -- encrypted the same way as the client secret since the PaymentIntent contains it
+  payload BYTEA NOT NULL,
+  attempts INTEGER NOT NULL DEFAULT 0,
+  last_error VARCHAR,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  published_at TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS payment_outbox_pending_idx ON payment_outbox (id) WHERE published_at IS NULL;
@@ -0,0 +1,43 @@
+use std::{
+  sync::Arc,
+  time::Duration,
+};
+use eyre::Result;
+use tracing::{info, warn, error};
+use solana_sdk::native_token::lamports_to_sol;
+use crate::utils::store::Store;
+
+async fn check_balances(store: &Store) -> Result<()> {
+  // The balances are exposed to monitoring by the metrics endpoint
+  let balances = store.tx_sender.refresh_balances().await?;
+
+  for (operator, balance) in balances {
+    if balance < store.config.operator_balance_critical {
+      error!("Operator {} balance is critically low: {} SOL. It will not be used for new reservations", operator, lamports_to_sol(balance));
+    } else if balance < store.config.operator_balance_warning {
+      warn!("Operator {} balance is low: {} SOL", operator, lamports_to_sol(balance));
+    } else {
+      info!("Operator {} balance: {} SOL", operator, lamports_to_sol(balance));
+    }
+  }
+
+  if !store.tx_sender.has_funded_operator() {
+    error!("No operator has enough funds. New checkouts are rejected");
+  }
+
+  Ok(())
+}
+
+/// Periodically checks the balance of each operator. The last known balances are used to skip operators
+/// without funds and to reject new checkouts when none of them can pay for the reservations.
+pub async fn run(store: Arc<Store>) {
+  let mut interval = tokio::time::interval(Duration::from_secs(store.config.balance_check_interval));
+
+  loop {
+    interval.tick().await;
+
+    if let Err(error) = check_balances(&store).await {
+      println!("Failed to check the operator balances: {:?}", error);
+    }
+  }
+}
@@ -0,0 +1,46 @@
+use std::{
+  fmt::Write,
+  sync::Arc,
+};
+use actix_web::{web, App, HttpServer, HttpResponse};
+use crate::utils::store::Store;
+
+/// Renders the gauges in the Prometheus text format
+fn render_metrics(store: &Store) -> String {
+  let mut metrics = String::new();
+
+  writeln!(metrics, "# HELP operator_balance_lamports Last known balance of the operator").unwrap();
+  writeln!(metrics, "# TYPE operator_balance_lamports gauge").unwrap();
+  for (operator, balance) in store.tx_sender.balances() {
+    writeln!(metrics, "operator_balance_lamports{{operator=\"{}\"}} {}", operator, balance).unwrap();
+  }
+
+  writeln!(metrics, "# HELP operator_funded Whether an operator can pay for new reservations. Checkouts are rejected at 0").unwrap();
+  writeln!(metrics, "# TYPE operator_funded gauge").unwrap();
+  writeln!(metrics, "operator_funded {}", store.tx_sender.has_funded_operator() as u8).unwrap();
+
+  metrics
+}
+
+async fn metrics(store: web::Data<Arc<Store>>) -> HttpResponse {
+  HttpResponse::Ok()
+  .content_type("text/plain; version=0.0.4")
+  .body(render_metrics(&store))
+}
+
+/// Serves `/metrics` for Prometheus. The balances are the ones `balance_monitor` refreshes.
+pub async fn run(store: Arc<Store>) {
+  let port = store.config.metrics_port;
+  let server = HttpServer::new(move || {
+    App::new()
+    .app_data(web::Data::new(Arc::clone(&store)))
+    .route("/metrics", web::get().to(metrics))
+  })
+  .bind(("0.0.0.0", port))
+  .unwrap()
+  .run();
+
+  if let Err(error) = server.await {
+    println!("Metrics server stopped: {:?}", error);
+  }
+}
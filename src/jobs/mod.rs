@@ -0,0 +1,4 @@
+pub mod rent_sweeper;
+pub mod balance_monitor;
+pub mod outbox_relay;
+pub mod metrics;
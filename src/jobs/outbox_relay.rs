@@ -0,0 +1,24 @@
+use std::{
+  sync::Arc,
+  time::Duration,
+};
+use tracing::info;
+use crate::{
+  utils::store::Store,
+  services::outbox::relay_pending,
+};
+
+/// Periodically publishes the PaymentIntent replies that could not be published when they were created
+pub async fn run(store: Arc<Store>) {
+  let mut interval = tokio::time::interval(Duration::from_secs(store.config.outbox_relay_interval));
+
+  loop {
+    interval.tick().await;
+
+    match relay_pending(&store).await {
+      Ok(0) => {},
+      Ok(published) => info!("Relayed {} pending payment replies", published),
+      Err(error) => println!("Failed to relay the pending payment replies: {:?}", error),
+    }
+  }
+}
@@ -0,0 +1,174 @@
+use std::{
+  sync::Arc,
+  time::Duration,
+};
+use borsh::BorshDeserialize;
+use eyre::Result;
+use tracing::info;
+use solana_client::{
+  nonblocking::rpc_client::RpcClient,
+  rpc_config::RpcProgramAccountsConfig,
+  rpc_filter::{RpcFilterType, Memcmp, MemcmpEncodedBytes},
+};
+use solana_sdk::{
+  pubkey::Pubkey,
+  hash::hash,
+  instruction::{AccountMeta, Instruction},
+  native_token::lamports_to_sol,
+};
+use program_artifacts::{
+  ix::InstructionData,
+  ticket_sale::{
+    self,
+    instruction::CloseSeatReservationIx,
+    account_data::SeatReservation,
+  },
+  secondary_market::{
+    self,
+    instruction::CloseSellListingReservationIx,
+    account_data::SellListingReservation,
+  },
+};
+use crate::utils::store::Store;
+
+/// Both reservation accounts store the operator that paid their rent right after the discriminator.
+/// `tests/rent_sweeper.rs` checks it against the accounts the programs create.
+const PAYER_OFFSET: usize = 8;
+
+/// Anchor prefixes each account with the first 8 bytes of sha256("account:<AccountName>")
+fn account_discriminator(account_name: &str) -> Vec<u8> {
+  hash(format!("account:{}", account_name).as_bytes()).to_bytes()[..8].to_vec()
+}
+
+/// Returns the accounts of the given type, funded by `operator`, whose `valid_until` slot has passed along with their balance
+async fn expired_accounts<T: BorshDeserialize>(
+  rpc_client: &RpcClient,
+  program_id: &Pubkey,
+  account_name: &str,
+  operator: &Pubkey,
+  valid_until: fn(&T) -> u64,
+) -> Result<Vec<(Pubkey, u64)>> {
+  let latest_slot = rpc_client.get_slot().await?;
+  let config = RpcProgramAccountsConfig {
+    filters: Some(vec![
+      RpcFilterType::Memcmp(Memcmp {
+        offset: 0,
+        bytes: MemcmpEncodedBytes::Bytes(account_discriminator(account_name)),
+        encoding: None,
+      }),
+      // Other services reserve seats through the same programs so we only close the accounts we paid for
+      RpcFilterType::Memcmp(Memcmp {
+        offset: PAYER_OFFSET,
+        bytes: MemcmpEncodedBytes::Bytes(operator.to_bytes().to_vec()),
+        encoding: None,
+      }),
+    ]),
+    ..Default::default()
+  };
+
+  let accounts = rpc_client.get_program_accounts_with_config(program_id, config).await?
+  .into_iter()
+  .filter_map(|(pubkey, account)| {
+    let data = T::deserialize(&mut &account.data[8..]).ok()?;
+
+    if latest_slot > valid_until(&data) {
+      Some((pubkey, account.lamports))
+    } else {
+      None
+    }
+  })
+  .collect();
+
+  Ok(accounts)
+}
+
+/// Closes the given accounts and returns the total lamports reclaimed net of the tx fees. The rent is sent
+/// to the operator that signs the close tx i.e. the one that funded the accounts.
+async fn close_accounts(
+  store: &Store,
+  operator: &Pubkey,
+  accounts: Vec<(Pubkey, u64)>,
+  close_ix: impl Fn(Pubkey, Pubkey) -> Instruction,
+) -> u64 {
+  let mut reclaimed = 0;
+
+  for (account, lamports) in accounts {
+    match store.tx_sender.send_tx_as(operator, |operator| Ok(close_ix(account, operator))).await {
+      Ok(sent_tx) => reclaimed += lamports.saturating_sub(sent_tx.fee),
+      Err(error) => println!("Failed to close expired reservation {}: {:?}", account, error),
+    }
+  }
+
+  reclaimed
+}
+
+pub async fn expired_seat_reservations(rpc_client: &RpcClient, operator: &Pubkey) -> Result<Vec<(Pubkey, u64)>> {
+  expired_accounts::<SeatReservation>(
+    rpc_client,
+    &ticket_sale::program_id(),
+    "SeatReservation",
+    operator,
+    |seat_reservation| seat_reservation.valid_until,
+  ).await
+}
+
+pub async fn expired_sell_listing_reservations(rpc_client: &RpcClient, operator: &Pubkey) -> Result<Vec<(Pubkey, u64)>> {
+  expired_accounts::<SellListingReservation>(
+    rpc_client,
+    &secondary_market::program_id(),
+    "SellListingReservation",
+    operator,
+    |sell_listing_reservation| sell_listing_reservation.valid_until,
+  ).await
+}
+
+async fn sweep(store: &Store, rpc_client: &RpcClient, operator: &Pubkey) -> Result<u64> {
+  let seat_reservations = expired_seat_reservations(rpc_client, operator).await?;
+  let sell_listing_reservations = expired_sell_listing_reservations(rpc_client, operator).await?;
+
+  info!(
+    "Found {} expired seat reservations and {} expired sell listing reservations funded by {}",
+    seat_reservations.len(),
+    sell_listing_reservations.len(),
+    operator,
+  );
+
+  let mut reclaimed = close_accounts(store, operator, seat_reservations, |seat_reservation, operator| Instruction {
+    program_id: ticket_sale::program_id(),
+    accounts: vec![
+      AccountMeta::new_readonly(store.config.ticket_sale_state, false),
+      AccountMeta::new(seat_reservation, false),
+      AccountMeta::new(operator, true),
+    ],
+    data: CloseSeatReservationIx {}.data(),
+  }).await;
+
+  reclaimed += close_accounts(store, operator, sell_listing_reservations, |sell_listing_reservation, operator| Instruction {
+    program_id: secondary_market::program_id(),
+    accounts: vec![
+      AccountMeta::new_readonly(store.config.secondary_market_state, false),
+      AccountMeta::new(sell_listing_reservation, false),
+      AccountMeta::new(operator, true),
+    ],
+    data: CloseSellListingReservationIx {}.data(),
+  }).await;
+
+  Ok(reclaimed)
+}
+
+/// Periodically closes the reservation accounts that have expired so that the operator gets back the rent
+pub async fn run(store: Arc<Store>) {
+  let rpc_client = RpcClient::new(store.config.rpc_endpoint.clone());
+  let mut interval = tokio::time::interval(Duration::from_secs(store.config.rent_sweeper_interval));
+
+  loop {
+    interval.tick().await;
+
+    for operator in store.tx_sender.operators() {
+      match sweep(&store, &rpc_client, &operator).await {
+        Ok(reclaimed) => info!("Reclaimed {} SOL from expired reservations funded by {}", lamports_to_sol(reclaimed), operator),
+        Err(error) => println!("Failed to sweep expired reservations funded by {}: {:?}", operator, error),
+      }
+    }
+  }
+}
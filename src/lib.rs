@@ -2,3 +2,4 @@ pub mod utils;
 pub mod models;
 pub mod queue;
 pub mod services;
+pub mod jobs;
@@ -5,12 +5,20 @@ use std::{
   process,
 };
 use actix::prelude::*;
-use amqp_helpers::consumer::consumer_runner::ConsumerRunner;
+use futures::future::try_join_all;
 use fiat_checkout_manager::{
   utils::store::Store,
-  queue::create_payment_consumer::CreatePaymentHandler,
+  queue::{create_payment_consumer::CreatePaymentHandler, consumer::Consumer},
+  jobs::{rent_sweeper, balance_monitor, outbox_relay, metrics},
 };
 
+/// Carts with at least one primary ticket go to the primary queue.
+/// `create_payment` is the queue from before the split. The API still publishes there until it routes by sale type,
+/// so we keep draining it; remove it once it stays empty.
+const PRIMARY_QUEUE: &str = "create_payment.primary";
+const SECONDARY_QUEUE: &str = "create_payment.secondary";
+const LEGACY_QUEUE: &str = "create_payment";
+
 fn main() {
   let orig_hook = panic::take_hook();
   panic::set_hook(Box::new(move |panic_info| {
@@ -28,15 +36,25 @@ fn main() {
 
   let execution = async {
     let store = Arc::new(Store::new().await);
+    actix::spawn(rent_sweeper::run(Arc::clone(&store)));
+    actix::spawn(balance_monitor::run(Arc::clone(&store)));
+    actix::spawn(outbox_relay::run(Arc::clone(&store)));
+    actix::spawn(metrics::run(Arc::clone(&store)));
+
+    // Each queue has its own channel and prefetch count so a busy queue can't take the capacity of the others
+    let handler = Arc::new(CreatePaymentHandler::new(Arc::clone(&store)));
+    let queues = [
+      (PRIMARY_QUEUE, store.config.primary_concurrency),
+      (SECONDARY_QUEUE, store.config.secondary_concurrency),
+      (LEGACY_QUEUE, store.config.legacy_concurrency),
+    ];
+    let mut consumers = Vec::with_capacity(queues.len());
 
-    let mut role_handler_consumer = ConsumerRunner::new(
-      store.config.rabbitmq_uri.clone(),
-      "create_payment".to_owned(),
-      "create_payment".to_owned(),
-      Arc::new(CreatePaymentHandler::new(store)),
-    ).await;
+    for (queue, concurrency) in queues {
+      consumers.push(Consumer::new(&store.config.rabbitmq_uri, queue, concurrency, Arc::clone(&handler)).await);
+    }
 
-    role_handler_consumer.start().await.unwrap();
+    try_join_all(consumers.iter().map(|consumer| consumer.start())).await.unwrap();
   };
 
   let arbiter = Arbiter::new();
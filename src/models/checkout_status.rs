@@ -0,0 +1,53 @@
+use std::io::{self, Write};
+use borsh::{BorshSerialize, BorshDeserialize};
+use serde::{Serialize, Deserialize};
+use super::envelope::{Versioned, unsupported_version};
+
+/// The steps of a checkout in the order they run
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum CheckoutStage {
+  Verification,
+  Allocation,
+  Validation,
+  Reservation,
+  Payment,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum CheckoutStatus {
+  SeatsAllocated,
+  /// The reservation txs are being sent
+  ReservationSent,
+  ReservationConfirmed,
+  PaymentCreated,
+  Failed {
+    stage: CheckoutStage,
+    error: String,
+  },
+}
+
+/// Progress of a checkout sent to the websocket session before the final `PaymentIntent`
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone)]
+pub struct CheckoutStatusUpdate {
+  pub ws_session_id: String,
+  pub status: CheckoutStatus,
+}
+
+impl Versioned for CheckoutStatusUpdate {
+  const VERSION: u16 = 1;
+  const LEGACY_LAYOUT: u16 = 1;
+
+  fn decode(version: u16, buf: &mut &[u8]) -> io::Result<Self> {
+    match version {
+      1 => Self::deserialize(buf),
+      _ => Err(unsupported_version(version)),
+    }
+  }
+
+  fn encode<W: Write>(&self, version: u16, writer: &mut W) -> io::Result<()> {
+    match version {
+      1 => self.serialize(writer),
+      _ => Err(unsupported_version(version)),
+    }
+  }
+}
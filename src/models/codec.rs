@@ -0,0 +1,65 @@
+use std::io::{self, Write};
+use borsh::{BorshSerialize, BorshDeserialize};
+use eyre::Result;
+use serde::{Serialize, de::DeserializeOwned};
+
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+pub const BORSH_CONTENT_TYPE: &str = "application/x-borsh";
+
+/// Encoding of the queue messages. It's selected by the AMQP `content_type` property; anything other than
+/// `application/json` is Borsh.
+#[derive(Debug, Clone, Copy, PartialEq, BorshSerialize, BorshDeserialize)]
+pub enum Codec {
+  Borsh,
+  Json,
+}
+
+impl Codec {
+  pub fn from_content_type(content_type: Option<&str>) -> Self {
+    match content_type {
+      Some(content_type) if content_type.starts_with(JSON_CONTENT_TYPE) => Codec::Json,
+      _ => Codec::Borsh,
+    }
+  }
+
+  pub fn content_type(&self) -> &'static str {
+    match self {
+      Codec::Borsh => BORSH_CONTENT_TYPE,
+      Codec::Json => JSON_CONTENT_TYPE,
+    }
+  }
+
+  pub fn decode<T: BorshDeserialize + DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+    match self {
+      Codec::Borsh => Ok(T::try_from_slice(data)?),
+      Codec::Json => Ok(serde_json::from_slice(data)?),
+    }
+  }
+
+  pub fn encode<T: BorshSerialize + Serialize>(&self, msg: &T) -> Result<Vec<u8>> {
+    match self {
+      Codec::Borsh => Ok(msg.try_to_vec()?),
+      Codec::Json => Ok(serde_json::to_vec(msg)?),
+    }
+  }
+}
+
+/// The undecoded bytes of a message. Consumers that support more than one codec receive this and decode it
+/// themselves once they know the content type of the delivery.
+#[derive(Debug)]
+pub struct RawMessage(pub Vec<u8>);
+
+impl BorshSerialize for RawMessage {
+  fn serialize<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+    writer.write_all(&self.0)
+  }
+}
+
+impl BorshDeserialize for RawMessage {
+  fn deserialize(buf: &mut &[u8]) -> io::Result<Self> {
+    let data = buf.to_vec();
+    *buf = &[];
+
+    Ok(Self(data))
+  }
+}
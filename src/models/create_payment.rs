@@ -1,6 +1,27 @@
+use std::io::{self, Write};
 use borsh::{BorshSerialize, BorshDeserialize};
+use serde::{Serialize, Deserialize};
+use super::envelope::{Versioned, unsupported_version};
 
-#[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug)]
+/// A primary sale line of a cart. Buys `quantity` seats of the given ticket type.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct PrimaryCartItem {
+  pub sale_account: String,
+  pub ticket_type_index: u8,
+  pub quantity: u8,
+  pub recipient: String,
+}
+
+/// A secondary sale line of a cart i.e. a single sell listing.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct SecondaryCartItem {
+  pub sale_account: String,
+  pub ticket_nft: String,
+  pub ticket_type_index: u8,
+  pub recipient: String,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, PartialEq, Debug)]
 pub enum CreatePayment {
   Primary {
     ws_session_id: String,
@@ -18,10 +39,74 @@ pub enum CreatePayment {
     ticket_nft: String,
     ticket_type_index: u8,
     recipient: String,
-  }
+  },
+  Cart {
+    ws_session_id: String,
+    buyer_uid: String,
+    event_id: String,
+    primary_items: Vec<PrimaryCartItem>,
+    secondary_items: Vec<SecondaryCartItem>,
+  },
 }
 
 impl CreatePayment {
+  pub fn ws_session_id(&self) -> &str {
+    match self {
+      CreatePayment::Primary {ws_session_id, ..} => ws_session_id,
+      CreatePayment::Secondary {ws_session_id, ..} => ws_session_id,
+      CreatePayment::Cart {ws_session_id, ..} => ws_session_id,
+    }
+  }
+
+  pub fn buyer_uid(&self) -> &str {
+    match self {
+      CreatePayment::Primary {buyer_uid, ..} => buyer_uid,
+      CreatePayment::Secondary {buyer_uid, ..} => buyer_uid,
+      CreatePayment::Cart {buyer_uid, ..} => buyer_uid,
+    }
+  }
+
+  pub fn event_id(&self) -> &str {
+    match self {
+      CreatePayment::Primary {event_id, ..} => event_id,
+      CreatePayment::Secondary {event_id, ..} => event_id,
+      CreatePayment::Cart {event_id, ..} => event_id,
+    }
+  }
+
+  /// All distinct recipients of the tickets in this message
+  pub fn recipients(&self) -> Vec<&str> {
+    let mut recipients = match self {
+      CreatePayment::Primary {recipient, ..} => vec![recipient.as_str()],
+      CreatePayment::Secondary {recipient, ..} => vec![recipient.as_str()],
+      CreatePayment::Cart {primary_items, secondary_items, ..} => primary_items.iter()
+      .map(|item| item.recipient.as_str())
+      .chain(secondary_items.iter().map(|item| item.recipient.as_str()))
+      .collect(),
+    };
+
+    recipients.sort_unstable();
+    recipients.dedup();
+
+    recipients
+  }
+
+  /// Sets the recipient of the tickets that have none i.e. when the buyer has no wallet
+  pub fn fill_empty_recipients(&mut self, wallet: &str) {
+    let mut recipients = match self {
+      CreatePayment::Primary {recipient, ..} => vec![recipient],
+      CreatePayment::Secondary {recipient, ..} => vec![recipient],
+      CreatePayment::Cart {primary_items, secondary_items, ..} => primary_items.iter_mut()
+      .map(|item| &mut item.recipient)
+      .chain(secondary_items.iter_mut().map(|item| &mut item.recipient))
+      .collect(),
+    };
+
+    for empty_recipient in recipients.iter_mut().filter(|recipient| recipient.is_empty()) {
+      **empty_recipient = wallet.to_string();
+    }
+  }
+
   pub fn primary(&self) -> (&str, &str, &str, &str, u8, &str) {
     match self {
       CreatePayment::Primary {
@@ -57,4 +142,36 @@ impl CreatePayment {
       _ => panic!("should never call primary")
     }
   }
+
+  pub fn cart(&self) -> (&str, &str, &str, &[PrimaryCartItem], &[SecondaryCartItem]) {
+    match self {
+      CreatePayment::Cart {
+        ws_session_id,
+        buyer_uid,
+        event_id,
+        primary_items,
+        secondary_items,
+      } => (ws_session_id, buyer_uid, event_id, primary_items, secondary_items),
+      _ => panic!("should never call cart")
+    }
+  }
+}
+
+impl Versioned for CreatePayment {
+  const VERSION: u16 = 1;
+  const LEGACY_LAYOUT: u16 = 1;
+
+  fn decode(version: u16, buf: &mut &[u8]) -> io::Result<Self> {
+    match version {
+      1 => Self::deserialize(buf),
+      _ => Err(unsupported_version(version)),
+    }
+  }
+
+  fn encode<W: Write>(&self, version: u16, writer: &mut W) -> io::Result<()> {
+    match version {
+      1 => self.serialize(writer),
+      _ => Err(unsupported_version(version)),
+    }
+  }
 }
@@ -0,0 +1,112 @@
+use std::io::{self, Write, ErrorKind};
+use borsh::{BorshSerialize, BorshDeserialize};
+use serde::{Serialize, Deserialize};
+use chrono::Utc;
+use uuid::Uuid;
+
+/// First byte of an enveloped message. Legacy messages start with a Borsh enum variant index or the length
+/// of a string, neither of which will realistically be this value.
+pub const ENVELOPE_MARKER: u8 = 0xEE;
+/// Messages that were sent without an envelope
+pub const LEGACY_VERSION: u16 = 0;
+
+/// A message whose payload layout can change between versions
+pub trait Versioned: Sized {
+  /// The version new messages are encoded with
+  const VERSION: u16;
+  /// The payload layout of the messages that were sent before the envelope existed
+  const LEGACY_LAYOUT: u16;
+
+  /// Decodes a payload of the given version into the current type
+  fn decode(version: u16, buf: &mut &[u8]) -> io::Result<Self>;
+
+  /// Encodes the payload using the layout of the given version
+  fn encode<W: Write>(&self, version: u16, writer: &mut W) -> io::Result<()>;
+}
+
+pub fn unsupported_version(version: u16) -> io::Error {
+  io::Error::new(ErrorKind::InvalidData, format!("unsupported message version {}", version))
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Header {
+  pub version: u16,
+  pub message_id: String,
+  /// Unix timestamp in milliseconds
+  pub timestamp: i64,
+  pub correlation_id: Option<String>,
+}
+
+impl Header {
+  pub fn is_legacy(&self) -> bool {
+    self.version == LEGACY_VERSION
+  }
+
+  /// The correlation id of a reply to this message
+  pub fn reply_correlation_id(&self) -> Option<String> {
+    self.correlation_id.clone().or_else(|| {
+      (!self.message_id.is_empty()).then(|| self.message_id.clone())
+    })
+  }
+}
+
+/// Wraps the messages exchanged with the other services. Both the enveloped and the legacy format can be
+/// decoded, so producers and consumers can be upgraded independently.
+///
+/// In JSON the header fields sit next to the payload and the payload is always decoded as the current version
+/// since new fields can be added as optional ones.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Envelope<T> {
+  #[serde(flatten)]
+  pub header: Header,
+  pub payload: T,
+}
+
+impl<T: Versioned> Envelope<T> {
+  /// Creates a new message that will be encoded with the given version. `LEGACY_VERSION` skips the envelope.
+  pub fn new(payload: T, version: u16, correlation_id: Option<String>) -> Self {
+    Self {
+      header: Header {
+        version,
+        message_id: Uuid::new_v4().to_string(),
+        timestamp: Utc::now().timestamp_millis(),
+        correlation_id,
+      },
+      payload,
+    }
+  }
+}
+
+impl<T: Versioned> BorshSerialize for Envelope<T> {
+  fn serialize<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+    if self.header.is_legacy() {
+      return self.payload.encode(T::LEGACY_LAYOUT, writer)
+    }
+
+    writer.write_all(&[ENVELOPE_MARKER])?;
+    self.header.serialize(writer)?;
+    self.payload.encode(self.header.version, writer)
+  }
+}
+
+impl<T: Versioned> BorshDeserialize for Envelope<T> {
+  fn deserialize(buf: &mut &[u8]) -> io::Result<Self> {
+    if buf.first() != Some(&ENVELOPE_MARKER) {
+      return Ok(Self {
+        header: Header {
+          version: LEGACY_VERSION,
+          message_id: String::new(),
+          timestamp: 0,
+          correlation_id: None,
+        },
+        payload: T::decode(T::LEGACY_LAYOUT, buf)?,
+      })
+    }
+
+    *buf = &buf[1..];
+    let header = Header::deserialize(buf)?;
+    let payload = T::decode(header.version, buf)?;
+
+    Ok(Self {header, payload})
+  }
+}
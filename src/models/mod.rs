@@ -1,2 +1,5 @@
 pub mod payment_intent;
 pub mod create_payment;
+pub mod envelope;
+pub mod codec;
+pub mod checkout_status;
@@ -1,13 +1,47 @@
+use std::io::{self, Write};
 use borsh::{BorshSerialize, BorshDeserialize};
+use serde::{Serialize, Deserialize};
+use super::envelope::{Versioned, unsupported_version};
 
-#[derive(Debug, BorshSerialize, BorshDeserialize)]
+#[derive(Debug, Clone, BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
 pub enum PaymentSecret {
   Ok(String),
   Err(String),
 }
 
-#[derive(BorshSerialize, BorshDeserialize)]
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
 pub struct PaymentIntent {
   pub ws_session_id: String,
   pub payment_secret: PaymentSecret,
+  /// The custodial wallet the tickets will be sent to if the buyer didn't provide a recipient
+  pub custodial_wallet: Option<String>,
+}
+
+/// Version 1 is the original layout without the custodial wallet
+impl Versioned for PaymentIntent {
+  const VERSION: u16 = 2;
+  const LEGACY_LAYOUT: u16 = 1;
+
+  fn decode(version: u16, buf: &mut &[u8]) -> io::Result<Self> {
+    match version {
+      1 => Ok(Self {
+        ws_session_id: String::deserialize(buf)?,
+        payment_secret: PaymentSecret::deserialize(buf)?,
+        custodial_wallet: None,
+      }),
+      2 => Self::deserialize(buf),
+      _ => Err(unsupported_version(version)),
+    }
+  }
+
+  fn encode<W: Write>(&self, version: u16, writer: &mut W) -> io::Result<()> {
+    match version {
+      1 => {
+        self.ws_session_id.serialize(writer)?;
+        self.payment_secret.serialize(writer)
+      },
+      2 => self.serialize(writer),
+      _ => Err(unsupported_version(version)),
+    }
+  }
 }
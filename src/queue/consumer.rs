@@ -0,0 +1,91 @@
+use std::sync::Arc;
+use eyre::Result;
+use futures::StreamExt;
+use amqp_helpers::core::types::Handler;
+use lapin::{
+  message::Delivery,
+  options::{BasicAckOptions, BasicConsumeOptions, BasicNackOptions, BasicQosOptions},
+  types::{AMQPValue, FieldTable},
+  Channel, Connection, ConnectionProperties,
+};
+use crate::models::codec::RawMessage;
+
+/// Number of times the message went through the retry queue
+fn retry_count(delivery: &Delivery) -> i64 {
+  let deaths = delivery.properties.headers().as_ref()
+  .and_then(|headers| headers.inner().get("x-death").cloned());
+
+  match deaths {
+    Some(AMQPValue::FieldArray(deaths)) => deaths.as_slice().iter()
+    .filter_map(|death| match death {
+      AMQPValue::FieldTable(death) => match death.inner().get("count")? {
+        AMQPValue::LongLongInt(count) => Some(*count),
+        _ => None,
+      },
+      _ => None,
+    })
+    .sum(),
+    _ => 0,
+  }
+}
+
+/// Consumes a queue with at most `concurrency` messages being handled at the same time. `ConsumerRunner` of the
+/// amqp-helpers version we use has no prefetch count so the channel is set up here. The queues and their retry
+/// queues are still declared by the producers through `RetryProducer`.
+pub struct Consumer<H> {
+  _connection: Connection,
+  channel: Channel,
+  queue: String,
+  handler: Arc<H>,
+}
+
+impl<H> Consumer<H>
+where
+  H: Handler<RawMessage> + 'static,
+{
+  pub async fn new(rabbitmq_uri: &str, queue: &str, concurrency: u16, handler: Arc<H>) -> Self {
+    let connection = Connection::connect(rabbitmq_uri, ConnectionProperties::default()).await.unwrap();
+    let channel = connection.create_channel().await.unwrap();
+    channel.basic_qos(concurrency, BasicQosOptions::default()).await.unwrap();
+
+    Self {
+      _connection: connection,
+      channel,
+      queue: queue.to_string(),
+      handler,
+    }
+  }
+
+  /// Messages are acked only once they have been handled, so the prefetch count bounds the handlers in flight
+  pub async fn start(&self) -> Result<()> {
+    let mut deliveries = self.channel.basic_consume(
+      &self.queue,
+      &self.queue,
+      BasicConsumeOptions::default(),
+      FieldTable::default(),
+    ).await?;
+
+    while let Some(delivery) = deliveries.next().await {
+      let delivery = delivery?;
+      let handler = Arc::clone(&self.handler);
+
+      actix::spawn(async move {
+        let result = handler.handle(RawMessage(delivery.data.clone()), &delivery, retry_count(&delivery)).await;
+        let result = match result {
+          Ok(_) => delivery.ack(BasicAckOptions::default()).await,
+          Err(error) => {
+            println!("Failed to handle a message. It will be retried: {:?}", error);
+            // Without requeue the message is dead-lettered to the retry queue
+            delivery.nack(BasicNackOptions::default()).await
+          }
+        };
+
+        if let Err(error) = result {
+          println!("Failed to settle a message: {:?}", error);
+        }
+      });
+    }
+
+    Ok(())
+  }
+}
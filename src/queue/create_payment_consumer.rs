@@ -1,15 +1,17 @@
 use std::{
   sync::Arc,
   str::FromStr,
+  future::Future,
 };
-use eyre::{Result, ContextCompat};
+use eyre::{Result, Report};
 use ticketland_api::services::ticket_availability::get_next_seat_index;
 use tracing::info;
-use chrono::Duration;
+use chrono::{Duration, Utc};
 use amqp_helpers::core::types::Handler;
 use async_trait::async_trait;
 use lapin::{
   message::{Delivery},
+  types::AMQPValue,
 };
 use solana_sdk::{
   pubkey::Pubkey,
@@ -39,24 +41,101 @@ use crate::{
   models::{
     create_payment::CreatePayment,
     payment_intent::{PaymentIntent, PaymentSecret},
+    envelope::{Envelope, Header},
+    codec::{Codec, RawMessage},
+    checkout_status::{CheckoutStage, CheckoutStatus},
+  },
+  utils::{
+    store::Store,
+    error::CheckoutError,
+  },
+  queue::status_producer::StatusReporter,
+  services::{
+    seat_allocation::{allocate_seats, group_by_ticket_type, assign_seats},
+    recipient::{RecipientSignature, verify_recipient},
+    custodial_wallet::custodial_wallet,
+    ticket_hold::{hold_tickets, release_tickets},
+    buyer_limits::{check_rate_limit, check_hold_limit, track_holds, untrack_holds},
+    message_dedup::{message_key, message_lock, processed_reply, save_reply},
+    event_partition::with_partition_slot,
+    outbox::{PaymentAttempt, record_payment, mark_published},
+    ticket_purchase::{CheckoutTicket, validate_tickets, lamports_to_stripe_unit, sell_listing_account},
+    stripe::{payment_metadata, create_payment},
   },
-  utils::store::Store,
-  services::stripe::{create_primary_sale_payment, create_secondary_sale_payment},
 };
 
 // TODO: We can potentially utilize an external service that will give us the average slot for the last day.
 // The Solana target slot time is 400ms but we give 50% margin to that ideal value.
 const SOLANA_SLOT_TIME: i64 = 600; // 600 ms
+const SEAT_RESERVATION_DURATION: i64 = 10; // 10 minutes
+const SELL_LISTING_RESERVATION_DURATION: i64 = 5; // 5 minutes
+/// Covers the reservation txs of a whole cart including the re-sends
+const MESSAGE_LOCK_DURATION: i64 = 5; // 5 minutes
+/// Maximum number of tickets in a single cart. The tickets are reserved one after the other and each reservation
+/// waits for confirmation, so this keeps a whole cart well within `MESSAGE_LOCK_DURATION`.
+const MAX_CART_SIZE: usize = 10;
+
+/// Signature of the buyer over the recipient and the time it was signed at. See `recipient_delegation_message`.
+const RECIPIENT_SIGNATURE_HEADER: &str = "recipient_signature";
+const RECIPIENT_SIGNATURE_TIMESTAMP_HEADER: &str = "recipient_signature_timestamp";
+
+fn header(delivery: &Delivery, name: &str) -> Option<String> {
+  let headers = delivery.properties.headers().as_ref()?;
+
+  match headers.inner().get(name)? {
+    AMQPValue::LongString(value) => Some(String::from_utf8_lossy(value.as_bytes()).to_string()),
+    _ => None,
+  }
+}
+
+/// A signature without a valid timestamp is ignored and the recipient is then treated as unsigned
+fn recipient_signature(delivery: &Delivery) -> Option<RecipientSignature> {
+  Some(RecipientSignature {
+    signature: header(delivery, RECIPIENT_SIGNATURE_HEADER)?,
+    timestamp: header(delivery, RECIPIENT_SIGNATURE_TIMESTAMP_HEADER)?.parse::<i64>().ok()?,
+  })
+}
+
+/// Retried messages come back through the retry queue, which dead-letters them to the original queue
+fn is_retry(delivery: &Delivery) -> bool {
+  delivery.properties.headers().as_ref()
+  .map(|headers| headers.inner().contains_key("x-death"))
+  .unwrap_or(false)
+}
+
+fn is_custom_error(error: &Report) -> bool {
+  if error.downcast_ref::<CheckoutError>().is_some() {
+    return true
+  }
+
+  let error = error.to_string();
 
-fn is_custom_error(error: &str) -> bool {
   error == "Ticket unavailable"
   || error == "Invalid ticket_nft"
   || error == "Only fixed price ticket types are supported"
   || error == "Sell listing unavailable"
+  || error == "Invalid cart"
+}
+
+/// Retries a reservation on transient failures. Checkout errors e.g. a seat that is already minted are
+/// deterministic so they are returned straight away.
+async fn retry_reservation<F, Fut>(reserve: F) -> Result<u64>
+where
+  F: Fn() -> Fut,
+  Fut: Future<Output = Result<u64>>,
+{
+  let reserve = &reserve;
+
+  with_retry(None, None, move || async move {
+    match reserve().await {
+      Err(error) if error.downcast_ref::<CheckoutError>().is_some() => Ok(Err(error)),
+      result => result.map(Ok),
+    }
+  }).await?
 }
 
 pub struct CreatePaymentHandler {
-  store: Arc<Store>
+  store: Arc<Store>,
 }
 
 impl CreatePaymentHandler {
@@ -66,9 +145,14 @@ impl CreatePaymentHandler {
     }
   }
 
-  async fn reserve_seat(&self, msg: &CreatePayment, seat_index: u32, seat_name: String,) -> Result<()> {
-    let (_, _, sale_account, event_id, _, recipient) = msg.primary();
-
+  pub async fn reserve_seat(
+    &self,
+    sale_account: &str,
+    event_id: &str,
+    recipient: &str,
+    seat_index: u32,
+    seat_name: String,
+  ) -> Result<u64> {
     let sale = Pubkey::from_str(&sale_account)?;
     let seat_reservation_account = ticket_sale::pda::seat_reservation(&sale, seat_index, &seat_name.to_string()).0;
     let result = self.store.rpc_client.get_anchor_account_data::<SeatReservation>(&seat_reservation_account).await;
@@ -81,7 +165,8 @@ impl CreatePaymentHandler {
         event_id.to_string(),
         seat_index,
         seat_name.to_string(),
-        recipient.to_string()
+        recipient.to_string(),
+        Duration::minutes(SEAT_RESERVATION_DURATION),
       ).await
     }
 
@@ -92,7 +177,7 @@ impl CreatePaymentHandler {
     // we should still send the reserve seat as this might be a new request for a payment link so we need to
     // update the duration of the reservation which will happen in the reserve_seat Ix.
     if latest_slot > seat_reservation.valid_until {
-      return Ok(())
+      return Ok(0)
     }
 
     self.send_reserve_seat_tx(
@@ -101,10 +186,30 @@ impl CreatePaymentHandler {
       event_id.to_string(),
       seat_index,
       seat_name.to_string(),
-      recipient.to_string()
+      recipient.to_string(),
+      Duration::minutes(SEAT_RESERVATION_DURATION),
+    ).await
+  }
+
+  /// Releases a seat that was reserved for a checkout that could not be completed. There is no explicit
+  /// release instruction so we simply re-reserve the seat with a zero duration which makes it expire straight away.
+  pub async fn release_seat(&self, sale_account: &str, event_id: &str, recipient: &str, seat_index: u32, seat_name: &str) -> Result<()> {
+    let sale = Pubkey::from_str(&sale_account)?;
+    let seat_reservation_account = ticket_sale::pda::seat_reservation(&sale, seat_index, &seat_name.to_string()).0;
+
+    self.send_reserve_seat_tx(
+      sale,
+      seat_reservation_account,
+      event_id.to_string(),
+      seat_index,
+      seat_name.to_string(),
+      recipient.to_string(),
+      Duration::zero(),
     ).await
+    .map(|_| ())
   }
 
+  /// Returns the fee paid in lamports
   async fn send_reserve_seat_tx(
     &self,
     sale: Pubkey,
@@ -113,46 +218,43 @@ impl CreatePaymentHandler {
     seat_index: u32,
     seat_name: String,
     recipient: String,
-  ) -> Result<()> {
+    duration: Duration,
+  ) -> Result<u64> {
     let state = self.store.config.ticket_sale_state;
-    let operator = self.store.rpc_client.payer_key().context("invalid priv key")?;
-
-    let accounts = vec![
-      AccountMeta::new_readonly(state, false),
-      AccountMeta::new_readonly(sale, false),
-      AccountMeta::new(seat_reservation, false),
-      AccountMeta::new(operator, true),
-      AccountMeta::new_readonly(system_program::ID, false),
-      AccountMeta::new_readonly(Rent::id(), false),
-    ];
-
-    let duration = (Duration::minutes(10).num_milliseconds() / SOLANA_SLOT_TIME) as u64;
-    let data = ReserveSeatIx {
-      seat_index,
-      seat_name: seat_name.clone(),
-      duration,
-      recipient: pubkey_from_str(&recipient)?,
-    }.data();
-
-    let ix = Instruction {
-      program_id: ticket_sale::program_id(),
-      accounts,
-      data,
-    };
+    let duration = (duration.num_milliseconds() / SOLANA_SLOT_TIME) as u64;
+    let recipient = pubkey_from_str(&recipient)?;
+
+    let sent_tx = self.store.tx_sender.send_tx(|operator| {
+      let accounts = vec![
+        AccountMeta::new_readonly(state, false),
+        AccountMeta::new_readonly(sale, false),
+        AccountMeta::new(seat_reservation, false),
+        AccountMeta::new(operator, true),
+        AccountMeta::new_readonly(system_program::ID, false),
+        AccountMeta::new_readonly(Rent::id(), false),
+      ];
+
+      let data = ReserveSeatIx {
+        seat_index,
+        seat_name: seat_name.clone(),
+        duration,
+        recipient,
+      }.data();
+
+      Ok(Instruction {
+        program_id: ticket_sale::program_id(),
+        accounts,
+        data,
+      })
+    }).await?;
 
-    Ok(
-      self.store.rpc_client.send_tx(ix).await
-      .map(|tx_hash| println!("Reserved seat {}:{} for event {}: {:?}", seat_index, &seat_name, &event_id, tx_hash))?
-    )
+    println!("Reserved seat {}:{} for event {}: {:?}", seat_index, &seat_name, &event_id, sent_tx.signature);
+
+    Ok(sent_tx.fee)
   }
 
-  async fn reserve_sell_listing(&self, msg: &CreatePayment) -> Result<()> {
-    let (_, _, _, event_id, ticket_nft, _, recipient) = msg.secondary();
-    let state = self.store.config.secondary_market_state;
-    let ticket_nft_pubkey = Pubkey::from_str(&ticket_nft)?;
-    let ticket_matadata = ticket_nft_pda::ticket_metadata(&self.store.config.ticket_nft_state, &ticket_nft_pubkey).0;
-    let sell_listing = secondary_market::pda::sell_listing(&state, &event_id, &ticket_matadata,).0;
-    let sell_listing_reservation_account = secondary_market::pda::sell_listing_reservation(&sell_listing).0;
+  pub async fn reserve_sell_listing(&self, event_id: &str, ticket_nft: &str, recipient: &str) -> Result<u64> {
+    let (sell_listing, sell_listing_reservation_account) = self.sell_listing_accounts(event_id, ticket_nft)?;
     let result = self.store.rpc_client.get_anchor_account_data::<SellListingReservation>(&sell_listing_reservation_account).await;
 
     // Fails if the account does not exist
@@ -161,7 +263,8 @@ impl CreatePaymentHandler {
         event_id.to_string(),
         sell_listing,
         sell_listing_reservation_account,
-        recipient.to_string()
+        recipient.to_string(),
+        Duration::minutes(SELL_LISTING_RESERVATION_DURATION),
       ).await
     }
 
@@ -173,16 +276,37 @@ impl CreatePaymentHandler {
     // upadte the duration of the reservation which will happen in the reserve_seat Ix.
     let recipient = pubkey_from_str(recipient)?;
     if latest_slot > sell_listing_reservation.valid_until && sell_listing_reservation.recipient != recipient {
-      return Ok(())
+      return Ok(0)
     }
 
     self.send_reserve_sell_listing_tx(
       event_id.to_string(),
       sell_listing,
       sell_listing_reservation_account,
-      recipient.to_string()
+      recipient.to_string(),
+      Duration::minutes(SELL_LISTING_RESERVATION_DURATION),
     ).await
-    .map(|tx_hash| println!("Reserved fill listing for ticket_nft {} for event {}: {:?}", ticket_nft, &event_id, tx_hash))
+  }
+
+  /// Same as `release_seat` but for sell listings
+  pub async fn release_sell_listing(&self, event_id: &str, ticket_nft: &str, recipient: &str) -> Result<()> {
+    let (sell_listing, sell_listing_reservation_account) = self.sell_listing_accounts(event_id, ticket_nft)?;
+
+    self.send_reserve_sell_listing_tx(
+      event_id.to_string(),
+      sell_listing,
+      sell_listing_reservation_account,
+      recipient.to_string(),
+      Duration::zero(),
+    ).await
+    .map(|_| ())
+  }
+
+  fn sell_listing_accounts(&self, event_id: &str, ticket_nft: &str) -> Result<(Pubkey, Pubkey)> {
+    let sell_listing = sell_listing_account(&self.store, event_id, ticket_nft)?;
+    let sell_listing_reservation_account = secondary_market::pda::sell_listing_reservation(&sell_listing).0;
+
+    Ok((sell_listing, sell_listing_reservation_account))
   }
 
   async fn send_reserve_sell_listing_tx(
@@ -191,77 +315,44 @@ impl CreatePaymentHandler {
     sell_listing: Pubkey,
     sell_listing_reservation: Pubkey,
     recipient: String,
-  ) -> Result<()> {
+    duration: Duration,
+  ) -> Result<u64> {
     let state = self.store.config.secondary_market_state;
-    let operator = self.store.rpc_client.payer_key().context("invalid priv key")?;
-
-    let accounts = vec![
-      AccountMeta::new_readonly(state, false),
-      AccountMeta::new(sell_listing_reservation, false),
-      AccountMeta::new(operator, true),
-      AccountMeta::new_readonly(system_program::ID, false),
-      AccountMeta::new_readonly(Rent::id(), false),
-    ];
-
-    let duration = (Duration::minutes(5).num_milliseconds() / SOLANA_SLOT_TIME) as u64;
-    let data = ReserveSellListingIx {
-      sell_listing,
-      duration,
-      recipient: pubkey_from_str(&recipient)?,
-    }.data();
-
-    let ix = Instruction {
-      program_id: secondary_market::program_id(),
-      accounts,
-      data,
-    };
-
-    self.store.rpc_client.send_tx(ix)
-    .await
-    .map(|tx_hash| println!("Reserved sell listing {} for event {}: {:?}", &sell_listing, &event_id, tx_hash))
-  }
-
-  async fn create_primary_payment(&self, msg: &CreatePayment, seat_index: u32, seat_name: String, ticket_nft: &Pubkey) -> Result<String> {
-    let (_, buyer_uid, sale_account, event_id, ticket_type_index, recipient) = msg.primary();
-
-    Ok(
-      create_primary_sale_payment(
-        Arc::clone(&self.store),
-        buyer_uid.to_string(),
-        sale_account.to_string(),
-        event_id.to_string(),
-        ticket_nft.to_string(),
-        ticket_type_index,
-        recipient.to_string(),
-        seat_index,
-        seat_name,
-      ).await?
-    )
-  }
+    let duration = (duration.num_milliseconds() / SOLANA_SLOT_TIME) as u64;
+    let recipient = pubkey_from_str(&recipient)?;
+
+    let sent_tx = self.store.tx_sender.send_tx(|operator| {
+      let accounts = vec![
+        AccountMeta::new_readonly(state, false),
+        AccountMeta::new(sell_listing_reservation, false),
+        AccountMeta::new(operator, true),
+        AccountMeta::new_readonly(system_program::ID, false),
+        AccountMeta::new_readonly(Rent::id(), false),
+      ];
+
+      let data = ReserveSellListingIx {
+        sell_listing,
+        duration,
+        recipient,
+      }.data();
+
+      Ok(Instruction {
+        program_id: secondary_market::program_id(),
+        accounts,
+        data,
+      })
+    }).await?;
 
-  async fn create_secondary_sale_payment(&self, msg: &CreatePayment) -> Result<String> {
-    let (_, buyer_uid, sale_account, event_id, ticket_nft, ticket_type_index, recipient) = msg.secondary();
+    println!("Reserved sell listing {} for event {}: {:?}", &sell_listing, &event_id, sent_tx.signature);
 
-    Ok(
-      create_secondary_sale_payment(
-        Arc::clone(&self.store),
-        buyer_uid.to_string(),
-        sale_account.to_string(),
-        event_id.to_string(),
-        ticket_nft.to_string(),
-        ticket_type_index,
-        recipient.to_string(),
-      ).await?
-    )
+    Ok(sent_tx.fee)
   }
-}
 
-#[async_trait]
-impl Handler<CreatePayment> for CreatePaymentHandler {
-  async fn handle(&self, msg: CreatePayment, _: &Delivery, _: i64,) -> Result<()> {
-    let (ws_session_id, payment_secret) = match msg {
+  /// Picks the tickets that will be purchased. For primary sales this is where the seats are allocated.
+  async fn checkout_tickets(&self, msg: &CreatePayment) -> Result<Vec<CheckoutTicket>> {
+    match msg {
       CreatePayment::Primary {..} => {
-        let (ws_session_id, buyer_uid, _, event_id, ticket_type_index, _,) = msg.primary();
+        let (_, _, sale_account, event_id, ticket_type_index, recipient) = msg.primary();
 
         let seat_index = get_next_seat_index(
           &self.store.pg_pool,
@@ -271,65 +362,369 @@ impl Handler<CreatePayment> for CreatePaymentHandler {
           &EventId(event_id.to_string()),
           ticket_type_index
         ).await?;
-        let seat_name = seat_index.to_string();
 
-        let ticket_nft = ticket_nft_pda::ticket_nft(
-          &self.store.config.ticket_nft_state,
-          seat_index,
-          &EventId(event_id.to_string()).val(),
-          ticket_type_index,
-        )
-        .0;
-        info!("Creating new payment for user {} and ticket {} from event {}", buyer_uid, ticket_nft, event_id);
-
-        with_retry(None, None, || self.reserve_seat(&msg, seat_index, seat_name.clone())).await
-        .map_err(|error| {
-          println!("Failed to reserve seat {:?}:{:?} for event {}: {:?}", seat_index, seat_name, event_id, error);
-          error
-        })?;
-
-        match self.create_primary_payment(&msg, seat_index, seat_name.clone(), &ticket_nft).await {
-          Ok(payment_secret) => Ok((ws_session_id, PaymentSecret::Ok(payment_secret))),
-          Err(error) => {
-            // we don't want to nack if the ticket is unavailable. Instead we need to ack and
-            // push PaymentIntent message including the error
-            if is_custom_error(&error.to_string()) {
-              Ok((ws_session_id, PaymentSecret::Err(error.to_string())))
-            } else {
-              println!("{:?}", error);
-              Err(error)
-            }
-          }
-        }?
+        Ok(vec![self.primary_ticket(sale_account, event_id, ticket_type_index, recipient, seat_index)])
       },
       CreatePayment::Secondary {..} => {
-        let (ws_session_id, buyer_uid, _, event_id, ticket_nft, _, _) = msg.secondary();
-        info!("Creating new secondary payment for user {} and ticket {} from event {}", buyer_uid, ticket_nft, event_id);
-
-        with_retry(None, None, || self.reserve_sell_listing(&msg)).await
-        .map_err(|error| {
-          println!("Failed to reserve sell listing for ticket_nft {} and event {}: {:?}",  ticket_nft, event_id, error);
-          error
-        })?;
-
-        match self.create_secondary_sale_payment(&msg).await {
-          Ok(payment_secret) => Ok((ws_session_id, PaymentSecret::Ok(payment_secret))),
-          Err(error) => {
-            if is_custom_error(&error.to_string()) {
-              Ok((ws_session_id, PaymentSecret::Err(error.to_string())))
-            } else {
-              Err(error)
-            }
+        let (_, _, sale_account, _, ticket_nft, ticket_type_index, recipient) = msg.secondary();
+
+        Ok(vec![CheckoutTicket::Secondary {
+          sale_account: sale_account.to_string(),
+          ticket_nft: ticket_nft.to_string(),
+          ticket_type_index,
+          recipient: recipient.to_string(),
+        }])
+      },
+      CreatePayment::Cart {..} => {
+        let (_, _, event_id, primary_items, secondary_items) = msg.cart();
+
+        let total_tickets = primary_items.iter().map(|item| item.quantity as usize).sum::<usize>() + secondary_items.len();
+        if total_tickets == 0 || total_tickets > MAX_CART_SIZE || primary_items.iter().any(|item| item.quantity == 0) {
+          return Err(Report::msg("Invalid cart"))
+        }
+
+        let mut tickets = Vec::with_capacity(total_tickets);
+
+        for (ticket_type_index, items) in group_by_ticket_type(primary_items) {
+          let sale_account = &items[0].sale_account;
+          if items.iter().any(|item| item.sale_account != *sale_account) {
+            return Err(Report::msg("Invalid cart"))
           }
-        }?
+
+          // Seats of the same ticket type are allocated next to each other so that groups can sit together
+          let recipients = items.iter().map(|item| item.recipient.as_str()).collect::<Vec<_>>();
+          let seat_indexes = allocate_seats(
+            Arc::clone(&self.store),
+            sale_account,
+            event_id,
+            ticket_type_index,
+            &recipients,
+            items.iter().map(|item| item.quantity as usize).sum(),
+          ).await?;
+
+          for (item, seat_index) in assign_seats(&items, &seat_indexes) {
+            tickets.push(self.primary_ticket(&item.sale_account, event_id, ticket_type_index, &item.recipient, seat_index));
+          }
+        }
+
+        for item in secondary_items {
+          tickets.push(CheckoutTicket::Secondary {
+            sale_account: item.sale_account.clone(),
+            ticket_nft: item.ticket_nft.clone(),
+            ticket_type_index: item.ticket_type_index,
+            recipient: item.recipient.clone(),
+          });
+        }
+
+        Ok(tickets)
+      },
+    }
+  }
+
+  fn primary_ticket(&self, sale_account: &str, event_id: &str, ticket_type_index: u8, recipient: &str, seat_index: u32) -> CheckoutTicket {
+    let ticket_nft = ticket_nft_pda::ticket_nft(
+      &self.store.config.ticket_nft_state,
+      seat_index,
+      &EventId(event_id.to_string()).val(),
+      ticket_type_index,
+    )
+    .0;
+
+    CheckoutTicket::Primary {
+      sale_account: sale_account.to_string(),
+      ticket_nft: ticket_nft.to_string(),
+      ticket_type_index,
+      recipient: recipient.to_string(),
+      seat_index,
+      seat_name: seat_index.to_string(),
+    }
+  }
+
+  /// Reserves every ticket on-chain. Either all tickets are reserved or none; if any reservation fails
+  /// the ones that already succeeded are released before returning the error.
+  /// Returns the total fee paid in lamports.
+  async fn reserve_tickets(&self, event_id: &str, tickets: &[CheckoutTicket]) -> Result<u64> {
+    let mut network_fee = 0;
+
+    for (i, ticket) in tickets.iter().enumerate() {
+      let result = match ticket {
+        CheckoutTicket::Primary {sale_account, recipient, seat_index, seat_name, ..} => {
+          retry_reservation(|| self.reserve_seat(sale_account, event_id, recipient, *seat_index, seat_name.clone())).await
+        },
+        CheckoutTicket::Secondary {ticket_nft, recipient, ..} => {
+          retry_reservation(|| self.reserve_sell_listing(event_id, ticket_nft, recipient)).await
+        },
+      };
+
+      match result {
+        Ok(fee) => network_fee += fee,
+        Err(error) => {
+          println!("Failed to reserve ticket_nft {} for event {}: {:?}", ticket.ticket_nft(), event_id, error);
+          self.release_reservations(event_id, &tickets[..i]).await;
+
+          return Err(error)
+        }
+      }
+    }
+
+    Ok(network_fee)
+  }
+
+  /// Compensation for `reserve_tickets`. A failure here is not fatal since the reservation will anyway expire.
+  async fn release_reservations(&self, event_id: &str, tickets: &[CheckoutTicket]) {
+    for ticket in tickets {
+      let result = match ticket {
+        CheckoutTicket::Primary {sale_account, recipient, seat_index, seat_name, ..} => {
+          self.release_seat(sale_account, event_id, recipient, *seat_index, seat_name).await
+        },
+        CheckoutTicket::Secondary {ticket_nft, recipient, ..} => {
+          self.release_sell_listing(event_id, ticket_nft, recipient).await
+        },
+      };
+
+      if let Err(error) = result {
+        println!("Failed to release reservation for ticket_nft {} and event {}: {:?}", ticket.ticket_nft(), event_id, error);
+      }
+    }
+  }
+
+  /// Buyers without a wallet leave the recipient empty and receive the tickets in their custodial wallet
+  async fn provision_custodial_wallet(&self, msg: &mut CreatePayment) -> Result<Option<String>> {
+    if !msg.recipients().contains(&"") {
+      return Ok(None)
+    }
+
+    let wallet = custodial_wallet(&self.store, msg.buyer_uid()).await?;
+    msg.fill_empty_recipients(&wallet);
+
+    Ok(Some(wallet))
+  }
+
+  /// The checkout runs in stages ordered from the cheapest to the most expensive one:
+  ///
+  /// 1. validation: the recipient and pre purchase checks which have no side effects
+  /// 2. hold: the pending keys in Redis
+  /// 3. reservation: the on-chain reservation which costs the operator SOL
+  /// 4. payment: the Stripe PaymentIntent
+  ///
+  /// If a stage fails, the side effects of the previous stages are compensated.
+  pub async fn checkout(
+    &self,
+    msg: &mut CreatePayment,
+    recipient_signature: Option<&RecipientSignature>,
+    status: &StatusReporter<'_>,
+  ) -> Result<(String, Option<String>)> {
+    // Without funds every reservation would fail so there is no point in trying
+    if !self.store.tx_sender.has_funded_operator() {
+      return Err(CheckoutError::TemporarilyUnavailable)?
+    }
+
+    // Provisioning a custodial wallet calls an external service so buyers at their hold limit are turned away first
+    check_hold_limit(&self.store, msg.event_id(), msg.buyer_uid()).await?;
+
+    let custodial_wallet = self.provision_custodial_wallet(msg).await?;
+    let msg = &*msg;
+    let buyer_uid = msg.buyer_uid();
+    let event_id = msg.event_id();
+
+    for recipient in msg.recipients() {
+      // the custodial wallet is provided by the key management service for this very buyer
+      if Some(recipient) == custodial_wallet.as_deref() {
+        continue;
+      }
+
+      verify_recipient(&self.store, buyer_uid, recipient, recipient_signature).await?;
+    }
+
+    status.enter(CheckoutStage::Allocation);
+    let tickets = self.checkout_tickets(msg).await?;
+    let ticket_nfts = tickets.iter().map(CheckoutTicket::ticket_nft).collect::<Vec<_>>();
+    info!("Creating new payment for user {} and tickets {:?} from event {}", buyer_uid, ticket_nfts, event_id);
+    status.report(CheckoutStatus::SeatsAllocated).await;
+
+    status.enter(CheckoutStage::Validation);
+    let (price, fee) = validate_tickets(Arc::clone(&self.store), event_id, &tickets).await?;
+
+    let held_tickets = tickets.iter()
+    .map(|ticket| (ticket.ticket_nft(), ticket.ticket_type_index()))
+    .collect::<Vec<_>>();
+    track_holds(&self.store, event_id, buyer_uid, &held_tickets).await?;
+    // The holds belong to the session so that a redelivery of the request can take them over
+    if let Err(error) = hold_tickets(&self.store, event_id, &ticket_nfts, msg.ws_session_id()).await {
+      untrack_holds(&self.store, event_id, buyer_uid, &ticket_nfts).await;
+      return Err(error)
+    }
+
+    status.enter(CheckoutStage::Reservation);
+    status.report(CheckoutStatus::ReservationSent).await;
+    let network_fee = match self.reserve_tickets(event_id, &tickets).await {
+      Ok(network_fee) => network_fee,
+      Err(error) => {
+        self.release_holds(event_id, buyer_uid, &ticket_nfts).await;
+        return Err(error)
       }
     };
+    status.report(CheckoutStatus::ReservationConfirmed).await;
 
-    self.store.payment_producer.new_payment(PaymentIntent {
-      ws_session_id: ws_session_id.to_string(),
-      payment_secret,
-    }).await?;
+    status.enter(CheckoutStage::Payment);
+    let result = self.pay(msg, &tickets, price, fee, network_fee).await;
+
+    if result.is_err() {
+      self.release_reservations(event_id, &tickets).await;
+      self.release_holds(event_id, buyer_uid, &ticket_nfts).await;
+    } else {
+      status.report(CheckoutStatus::PaymentCreated).await;
+    }
+
+    result.map(|payment_secret| (payment_secret, custodial_wallet))
+  }
+
+  async fn release_holds(&self, event_id: &str, buyer_uid: &str, ticket_nfts: &[&str]) {
+    release_tickets(&self.store, event_id, ticket_nfts).await;
+    untrack_holds(&self.store, event_id, buyer_uid, ticket_nfts).await;
+  }
+
+  async fn pay(&self, msg: &CreatePayment, tickets: &[CheckoutTicket], price: i64, fee: i64, network_fee: u64) -> Result<String> {
+    // The operator has already paid for the reservation transactions so the buyer covers that cost too
+    let fee = fee + lamports_to_stripe_unit(Arc::clone(&self.store), network_fee).await?;
+    let payment_metadata = payment_metadata(&self.store, msg, tickets)?;
+
+    create_payment(
+      Arc::clone(&self.store),
+      msg.buyer_uid().to_string(),
+      msg.event_id().to_string(),
+      price,
+      fee,
+      Some(payment_metadata),
+    ).await
+  }
+
+  /// The buyer has most likely left if the request waited in the queue for too long. Enveloped messages carry
+  /// their creation time; for legacy ones we fall back to the AMQP timestamp if the producer set it.
+  fn is_expired(&self, msg_header: &Header, delivery: &Delivery) -> bool {
+    let created_at = if msg_header.is_legacy() {
+      match delivery.properties.timestamp() {
+        Some(timestamp) => *timestamp as i64 * 1000,
+        None => return false,
+      }
+    } else {
+      msg_header.timestamp
+    };
+
+    Utc::now().timestamp_millis() - created_at > self.store.config.max_message_age as i64 * 1000
+  }
+
+  /// Only the first delivery of a request counts towards the rate limit. Redeliveries and retries of a request
+  /// that failed with a transient error are the same request.
+  async fn check_rate_limit(&self, msg: &CreatePayment, delivery: &Delivery) -> Result<()> {
+    if delivery.redelivered || is_retry(delivery) {
+      return Ok(())
+    }
+
+    check_rate_limit(&self.store, msg.buyer_uid()).await
+  }
+
+  async fn process(&self, msg_header: Header, mut msg: CreatePayment, message_key: &str, delivery: &Delivery, codec: Codec) -> Result<()> {
+    if let Some(reply) = processed_reply(&self.store, message_key).await? {
+      info!("Message {} has already been processed. Replaying the reply", message_key);
+      return self.store.payment_producer.new_payment(&reply).await
+    }
+
+    let recipient_signature = recipient_signature(delivery);
+    let status = StatusReporter::new(
+      &self.store.status_producer,
+      msg.ws_session_id().to_string(),
+      msg_header.reply_correlation_id(),
+      codec,
+    );
+
+    let result = if self.is_expired(&msg_header, delivery) {
+      info!("Dropping expired message {} of session {}", message_key, msg.ws_session_id());
+      Err(CheckoutError::RequestExpired.into())
+    } else if let Err(error) = self.check_rate_limit(&msg, delivery).await {
+      Err(error)
+    } else {
+      self.checkout(&mut msg, recipient_signature.as_ref(), &status).await
+    };
+
+    let (payment_secret, custodial_wallet) = match result {
+      Ok((payment_secret, custodial_wallet)) => (PaymentSecret::Ok(payment_secret), custodial_wallet),
+      // we don't want to nack if the ticket is unavailable. Instead we need to ack and
+      // push PaymentIntent message including the error
+      Err(error) if is_custom_error(&error) => {
+        // Other errors are retried so only these ones are final
+        status.fail(error.to_string()).await;
+        (PaymentSecret::Err(error.to_string()), None)
+      },
+      Err(error) => {
+        println!("{:?}", error);
+        return Err(error)
+      }
+    };
+
+    let attempt = PaymentAttempt {
+      message_key,
+      ws_session_id: msg.ws_session_id(),
+      buyer_uid: msg.buyer_uid(),
+      event_id: msg.event_id(),
+      payment_secret: &payment_secret,
+    };
+    let reply = self.store.payment_producer.encode_payment(PaymentIntent {
+      ws_session_id: msg.ws_session_id().to_string(),
+      payment_secret: payment_secret.clone(),
+      custodial_wallet,
+    }, message_key, msg_header.reply_correlation_id(), codec)?;
+
+    // Once the reply is in the outbox it reaches the buyer even if publishing below fails. If it can't be stored
+    // the message is retried rather than publishing a reply that nothing would relay again.
+    let outbox_id = record_payment(&self.store, attempt, &reply).await?;
+
+    // Saved before publishing so that a redelivery replays this reply instead of checking out again
+    save_reply(&self.store, message_key, outbox_id).await?;
+
+    if let Err(error) = self.store.payment_producer.new_payment(&reply).await {
+      println!("Failed to publish the reply of {}. It will be relayed: {:?}", message_key, error);
+      return Ok(())
+    }
+
+    // Otherwise the relay publishes the reply again. Consumers drop the duplicate by its message id.
+    if let Err(error) = with_retry(None, None, || mark_published(&self.store, outbox_id)).await {
+      println!("Failed to mark the reply of {} as published: {:?}", message_key, error);
+    }
 
     Ok(())
   }
 }
+
+#[async_trait]
+impl Handler<RawMessage> for CreatePaymentHandler {
+  async fn handle(&self, raw_msg: RawMessage, delivery: &Delivery, _: i64,) -> Result<()> {
+    let codec = Codec::from_content_type(delivery.properties.content_type().as_ref().map(|content_type| content_type.as_str()));
+    let Envelope {header: msg_header, payload: msg} = match codec.decode::<Envelope<CreatePayment>>(&raw_msg.0) {
+      Ok(envelope) => envelope,
+      Err(error) => {
+        // Without the ws_session_id we can't reply to the buyer so the message is parked and acked
+        println!("Dead-lettering a message that can't be decoded: {:?}", error);
+        return self.store.dead_letter_producer.dead_letter(&raw_msg.0, codec, &error.to_string()).await
+      }
+    };
+    let message_key = message_key(&msg_header, delivery);
+    let message_lock_duration = Duration::minutes(MESSAGE_LOCK_DURATION);
+
+    let event_id = msg.event_id().to_string();
+
+    with_partition_slot(&self.store, &event_id, message_lock_duration, || async {
+      // A message can be redelivered while the consumer that received it first is still processing it. The lock is
+      // only taken once the slot is ours so that waiting for the slot can't eat into it.
+      let lock = self.store.redlock.lock(
+        &message_lock(&message_key),
+        message_lock_duration.num_milliseconds() as usize,
+      ).await?;
+
+      let result = self.process(msg_header, msg, &message_key, delivery, codec).await;
+      self.store.redlock.unlock(lock).await;
+
+      result
+    }).await
+  }
+}
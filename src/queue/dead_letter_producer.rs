@@ -0,0 +1,40 @@
+use eyre::Result;
+use amqp_helpers::producer::retry_producer::RetryProducer;
+use crate::models::codec::Codec;
+use super::publisher::Publisher;
+
+const ERROR_HEADER: &str = "x-error";
+
+/// Parks the messages we can't decode. Retrying them would fail the same way forever.
+pub struct DeadLetterProducer {
+  _producer: RetryProducer,
+  publisher: Publisher,
+}
+
+impl DeadLetterProducer {
+  pub async fn new(rabbitmq_uri: String, retry_ttl: u16) -> Self {
+    // Only used to declare the exchange and queue
+    let producer = RetryProducer::new(
+      &rabbitmq_uri,
+      &"create_payment_dead_letter",
+      &"create_payment_dead_letter",
+      &"create_payment_dead_letter.new",
+      retry_ttl,
+      None,
+    ).await.unwrap();
+
+    Self {
+      _producer: producer,
+      publisher: Publisher::new(&rabbitmq_uri).await,
+    }
+  }
+
+  pub async fn dead_letter(&self, msg: &[u8], codec: Codec, error: &str) -> Result<()> {
+    self.publisher.publish(
+      "create_payment_dead_letter",
+      "create_payment_dead_letter.new",
+      msg,
+      Publisher::with_header(Publisher::properties(codec), ERROR_HEADER, error),
+    ).await
+  }
+}
@@ -1,2 +1,6 @@
 pub mod payment_producer;
 pub mod create_payment_consumer;
+pub mod status_producer;
+pub mod publisher;
+pub mod dead_letter_producer;
+pub mod consumer;
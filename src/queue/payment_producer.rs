@@ -1,14 +1,33 @@
 use eyre::Result;
-use borsh::{BorshSerialize};
+use borsh::{BorshSerialize, BorshDeserialize};
 use amqp_helpers::producer::retry_producer::RetryProducer;
-use crate::models::payment_intent::PaymentIntent;
+use lapin::types::ShortString;
+use crate::models::{
+  payment_intent::PaymentIntent,
+  envelope::{Envelope, Versioned},
+  codec::Codec,
+};
+use super::publisher::Publisher;
+
+/// An encoded PaymentIntent along with the codec it's published with
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct Reply {
+  /// The key of the request. A reply can be published more than once i.e. by the outbox relay so consumers
+  /// should use it to drop duplicates.
+  pub message_id: String,
+  pub codec: Codec,
+  pub data: Vec<u8>,
+}
 
 pub struct PaymentProducer {
-  producer: RetryProducer,
+  /// Only used to declare the exchange and queue
+  _producer: RetryProducer,
+  publisher: Publisher,
+  version: u16,
 }
 
 impl PaymentProducer {
-  pub async fn new(rabbitmq_uri: String, retry_ttl: u16,) -> Self {
+  pub async fn new(rabbitmq_uri: String, retry_ttl: u16, version: u16) -> Self {
     let producer = RetryProducer::new(
       &rabbitmq_uri,
       &"payment_created",
@@ -19,15 +38,34 @@ impl PaymentProducer {
     ).await.unwrap();
 
     Self {
-      producer,
+      _producer: producer,
+      publisher: Publisher::new(&rabbitmq_uri).await,
+      version,
     }
   }
 
-  pub async fn new_payment(&self, msg: PaymentIntent) -> Result<()> {
-    self.producer.publish(
-      &"payment_created",
-      &"payment_created.new",
-      &msg.try_to_vec()?
+  /// Encodes the reply using the same codec as the request
+  pub fn encode_payment(&self, msg: PaymentIntent, message_id: &str, correlation_id: Option<String>, codec: Codec) -> Result<Reply> {
+    // JSON has no legacy format so it always uses the current version
+    let version = match codec {
+      Codec::Borsh => self.version,
+      Codec::Json => PaymentIntent::VERSION,
+    };
+
+    Ok(Reply {
+      message_id: message_id.to_string(),
+      codec,
+      data: codec.encode(&Envelope::new(msg, version, correlation_id))?,
+    })
+  }
+
+  /// Publishes a reply created with `encode_payment`
+  pub async fn new_payment(&self, reply: &Reply) -> Result<()> {
+    self.publisher.publish(
+      "payment_created",
+      "payment_created.new",
+      &reply.data,
+      Publisher::properties(reply.codec).with_message_id(ShortString::from(reply.message_id.as_str())),
     ).await
   }
 }
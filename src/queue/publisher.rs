@@ -0,0 +1,62 @@
+use eyre::{Result, Report};
+use lapin::{
+  options::{BasicPublishOptions, ConfirmSelectOptions},
+  types::{AMQPValue, FieldTable, ShortString},
+  BasicProperties, Channel, Connection, ConnectionProperties,
+};
+use crate::models::codec::Codec;
+
+/// Delivery mode of messages that survive a broker restart
+const PERSISTENT: u8 = 2;
+
+/// Publishes messages with their content type so that consumers can decode them without guessing the codec.
+/// The producers still declare their exchanges and queues through `RetryProducer`.
+pub struct Publisher {
+  _connection: Connection,
+  channel: Channel,
+}
+
+impl Publisher {
+  pub async fn new(rabbitmq_uri: &str) -> Self {
+    let connection = Connection::connect(rabbitmq_uri, ConnectionProperties::default()).await.unwrap();
+    let channel = connection.create_channel().await.unwrap();
+    channel.confirm_select(ConfirmSelectOptions::default()).await.unwrap();
+
+    Self {
+      _connection: connection,
+      channel,
+    }
+  }
+
+  pub fn properties(codec: Codec) -> BasicProperties {
+    BasicProperties::default()
+    .with_content_type(ShortString::from(codec.content_type()))
+    .with_delivery_mode(PERSISTENT)
+  }
+
+  /// Adds a string header to the given properties
+  pub fn with_header(properties: BasicProperties, name: &str, value: &str) -> BasicProperties {
+    let mut headers: FieldTable = properties.headers().clone().unwrap_or_default();
+    headers.insert(ShortString::from(name), AMQPValue::LongString(value.into()));
+
+    properties.with_headers(headers)
+  }
+
+  /// Returns once the broker has confirmed the message
+  pub async fn publish(&self, exchange: &str, routing_key: &str, data: &[u8], properties: BasicProperties) -> Result<()> {
+    let confirmation = self.channel.basic_publish(
+      exchange,
+      routing_key,
+      BasicPublishOptions::default(),
+      data,
+      properties,
+    ).await?
+    .await?;
+
+    if confirmation.is_nack() {
+      return Err(Report::msg(format!("Message to {} was rejected by the broker", exchange)))
+    }
+
+    Ok(())
+  }
+}
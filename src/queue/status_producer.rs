@@ -0,0 +1,87 @@
+use std::sync::Mutex;
+use eyre::Result;
+use amqp_helpers::producer::retry_producer::RetryProducer;
+use crate::models::{
+  checkout_status::{CheckoutStage, CheckoutStatus, CheckoutStatusUpdate},
+  envelope::{Envelope, Versioned},
+  codec::Codec,
+};
+use super::publisher::Publisher;
+
+pub struct StatusProducer {
+  /// Only used to declare the exchange and queue
+  _producer: RetryProducer,
+  publisher: Publisher,
+}
+
+impl StatusProducer {
+  pub async fn new(rabbitmq_uri: String, retry_ttl: u16,) -> Self {
+    let producer = RetryProducer::new(
+      &rabbitmq_uri,
+      &"checkout_status",
+      &"checkout_status",
+      &"checkout_status.new",
+      retry_ttl,
+      None,
+    ).await.unwrap();
+
+    Self {
+      _producer: producer,
+      publisher: Publisher::new(&rabbitmq_uri).await,
+    }
+  }
+
+  pub async fn new_status(&self, msg: CheckoutStatusUpdate, correlation_id: Option<String>, codec: Codec) -> Result<()> {
+    let msg = Envelope::new(msg, CheckoutStatusUpdate::VERSION, correlation_id);
+
+    self.publisher.publish(
+      "checkout_status",
+      "checkout_status.new",
+      &codec.encode(&msg)?,
+      Publisher::properties(codec),
+    ).await
+  }
+}
+
+/// Publishes the progress of a single checkout. Status updates are informational so failing to publish
+/// them never fails the checkout.
+pub struct StatusReporter<'a> {
+  producer: &'a StatusProducer,
+  ws_session_id: String,
+  correlation_id: Option<String>,
+  codec: Codec,
+  stage: Mutex<CheckoutStage>,
+}
+
+impl<'a> StatusReporter<'a> {
+  pub fn new(producer: &'a StatusProducer, ws_session_id: String, correlation_id: Option<String>, codec: Codec) -> Self {
+    Self {
+      producer,
+      ws_session_id,
+      correlation_id,
+      codec,
+      stage: Mutex::new(CheckoutStage::Verification),
+    }
+  }
+
+  /// Marks the beginning of a stage. A failure is reported against the last stage entered.
+  pub fn enter(&self, stage: CheckoutStage) {
+    *self.stage.lock().unwrap() = stage;
+  }
+
+  pub async fn report(&self, status: CheckoutStatus) {
+    let msg = CheckoutStatusUpdate {
+      ws_session_id: self.ws_session_id.clone(),
+      status,
+    };
+
+    if let Err(error) = self.producer.new_status(msg, self.correlation_id.clone(), self.codec).await {
+      println!("Failed to publish the checkout status of {}: {:?}", self.ws_session_id, error);
+    }
+  }
+
+  pub async fn fail(&self, error: String) {
+    let stage = *self.stage.lock().unwrap();
+    self.report(CheckoutStatus::Failed {stage, error}).await
+  }
+}
@@ -0,0 +1,221 @@
+use std::collections::{HashMap, HashSet};
+use chrono::{Duration, Utc};
+use eyre::Result;
+use diesel::{
+  QueryableByName, sql_query,
+  sql_types::{BigInt, Integer, Text},
+};
+use diesel_async::RunQueryDsl;
+use crate::utils::{
+  store::Store,
+  error::CheckoutError,
+  config::PurchaseLimits,
+};
+use super::ticket_hold::HOLD_DURATION;
+
+/// The holds are read and written under a lock so this only needs to cover a couple of Redis calls
+const LOCK_DURATION: i64 = 5; // 5 seconds
+
+#[derive(QueryableByName)]
+struct PurchasedTickets {
+  #[diesel(sql_type = Integer)]
+  ticket_type_index: i32,
+  #[diesel(sql_type = BigInt)]
+  count: i64,
+}
+
+fn rate_limit_key(buyer_uid: &str, window: i64) -> String {
+  format!("rate_limit:{}:{}", buyer_uid, window)
+}
+
+fn buyer_holds_key(event_id: &str, buyer_uid: &str) -> String {
+  format!("buyer_holds:{}:{}", event_id, buyer_uid)
+}
+
+/// The lock must not use the key of the counter itself as that's where RedLock keeps it
+fn lock_resource(redis_key: &str) -> Vec<u8> {
+  format!("lock:{}", redis_key).into_bytes()
+}
+
+/// Counts the requests of the buyer in fixed one minute windows
+pub async fn check_rate_limit(store: &Store, buyer_uid: &str) -> Result<()> {
+  let window = Utc::now().timestamp() / 60;
+  let redis_key = rate_limit_key(buyer_uid, window);
+  let mut redis = store.redis_pool.connection().await?;
+
+  // INCR is atomic so concurrent requests can't both read the same count
+  let requests = redis.incr(&redis_key).await?;
+  if requests == 1 {
+    redis.expire(&redis_key, Duration::minutes(1).num_milliseconds() as usize).await?;
+  }
+
+  if requests > store.config.max_requests_per_minute as i64 {
+    return Err(CheckoutError::RateLimited)?
+  }
+
+  Ok(())
+}
+
+/// A ticket the buyer holds. Stored as `ticket_nft:expires_at:ticket_type_index`. Entries written before
+/// purchase limits existed have no ticket type. They expire within `HOLD_DURATION` of the rollout; the `Option`
+/// can go after that.
+struct Hold {
+  ticket_nft: String,
+  expires_at: i64,
+  ticket_type_index: Option<u8>,
+}
+
+fn parse_holds(value: &str, now: i64) -> Vec<Hold> {
+  value.split(',')
+  .filter_map(|entry| {
+    let mut parts = entry.split(':');
+
+    Some(Hold {
+      ticket_nft: parts.next()?.to_string(),
+      expires_at: parts.next()?.parse::<i64>().ok()?,
+      ticket_type_index: parts.next().and_then(|ticket_type_index| ticket_type_index.parse::<u8>().ok()),
+    })
+  })
+  .filter(|hold| hold.expires_at > now)
+  .collect()
+}
+
+fn format_hold(hold: &Hold) -> String {
+  match hold.ticket_type_index {
+    Some(ticket_type_index) => format!("{}:{}:{}", hold.ticket_nft, hold.expires_at, ticket_type_index),
+    None => format!("{}:{}", hold.ticket_nft, hold.expires_at),
+  }
+}
+
+async fn update_holds<F>(store: &Store, event_id: &str, buyer_uid: &str, update: F) -> Result<()>
+where
+  F: FnOnce(Vec<Hold>, i64) -> Result<Vec<Hold>>,
+{
+  let redis_key = buyer_holds_key(event_id, buyer_uid);
+  let lock = store.redlock.lock(&lock_resource(&redis_key), Duration::seconds(LOCK_DURATION).num_milliseconds() as usize).await?;
+
+  let result: Result<()> = async {
+    let mut redis = store.redis_pool.connection().await?;
+    let now = Utc::now().timestamp_millis();
+    let holds = match redis.get(&redis_key).await {
+      Ok(value) => parse_holds(&value, now),
+      Err(_) => vec![],
+    };
+
+    let holds = update(holds, now)?;
+    if holds.is_empty() {
+      redis.delete(&redis_key).await?;
+      return Ok(())
+    }
+
+    let value = holds.iter().map(format_hold).collect::<Vec<_>>().join(",");
+    redis.set_ex(&redis_key, &value, Duration::minutes(HOLD_DURATION).num_milliseconds() as usize).await?;
+
+    Ok(())
+  }.await;
+
+  store.redlock.unlock(lock).await;
+
+  result
+}
+
+/// Number of tickets of the event the buyer has already purchased per ticket type
+async fn purchased_tickets(store: &Store, event_id: &str, buyer_uid: &str) -> Result<HashMap<u8, u32>> {
+  let mut connection = store.sql_pool.get().await?;
+  let rows = sql_query(
+    "SELECT ticket_type_index::INT, COUNT(*) AS count FROM tickets
+    WHERE account_id = $1 AND event_id = $2 GROUP BY ticket_type_index"
+  )
+  .bind::<Text, _>(buyer_uid)
+  .bind::<Text, _>(event_id)
+  .load::<PurchasedTickets>(&mut *connection)
+  .await?;
+
+  Ok(
+    rows.iter()
+    .map(|row| (row.ticket_type_index as u8, row.count as u32))
+    .collect()
+  )
+}
+
+/// Purchased and held tickets together with the new ones must stay within the limits of the event and its ticket types
+fn check_purchase_limits(
+  limits: &PurchaseLimits,
+  event_id: &str,
+  purchased: &HashMap<u8, u32>,
+  holds: &[Hold],
+) -> Result<()> {
+  if let Some(limit) = limits.event_limit(event_id) {
+    if purchased.values().sum::<u32>() + holds.len() as u32 > limit {
+      return Err(CheckoutError::PurchaseLimitReached(limit))?
+    }
+  }
+
+  let ticket_types = holds.iter().filter_map(|hold| hold.ticket_type_index).collect::<HashSet<_>>();
+  for ticket_type_index in ticket_types {
+    if let Some(limit) = limits.ticket_type_limit(event_id, ticket_type_index) {
+      let held = holds.iter().filter(|hold| hold.ticket_type_index == Some(ticket_type_index)).count() as u32;
+
+      if purchased.get(&ticket_type_index).copied().unwrap_or_default() + held > limit {
+        return Err(CheckoutError::PurchaseLimitReached(limit))?
+      }
+    }
+  }
+
+  Ok(())
+}
+
+/// Read-only check that the buyer can hold at least one more ticket. It runs before the checkout has any side
+/// effect so that a buyer at the limit is turned away early; `track_holds` still enforces the limit under the lock.
+pub async fn check_hold_limit(store: &Store, event_id: &str, buyer_uid: &str) -> Result<()> {
+  let mut redis = store.redis_pool.connection().await?;
+  let holds = match redis.get(&buyer_holds_key(event_id, buyer_uid)).await {
+    Ok(value) => parse_holds(&value, Utc::now().timestamp_millis()),
+    Err(_) => vec![],
+  };
+
+  if holds.len() >= store.config.max_active_holds {
+    return Err(CheckoutError::TooManyHolds)?
+  }
+
+  Ok(())
+}
+
+/// Records the tickets, given with their ticket type, the buyer is about to hold. Fails if that would take the
+/// buyer over the limit of simultaneous holds or over the purchase limits of the event.
+pub async fn track_holds(store: &Store, event_id: &str, buyer_uid: &str, tickets: &[(&str, u8)]) -> Result<()> {
+  let max_active_holds = store.config.max_active_holds;
+  let purchase_limits = &store.config.purchase_limits;
+  let purchased = purchased_tickets(store, event_id, buyer_uid).await?;
+
+  update_holds(store, event_id, buyer_uid, |mut holds, now| {
+    // A retried checkout tracks the same tickets again
+    holds.retain(|hold| !tickets.iter().any(|(ticket_nft, _)| *ticket_nft == hold.ticket_nft));
+
+    if holds.len() + tickets.len() > max_active_holds {
+      return Err(CheckoutError::TooManyHolds)?
+    }
+
+    let expires_at = now + Duration::minutes(HOLD_DURATION).num_milliseconds();
+    holds.extend(tickets.iter().map(|(ticket_nft, ticket_type_index)| Hold {
+      ticket_nft: ticket_nft.to_string(),
+      expires_at,
+      ticket_type_index: Some(*ticket_type_index),
+    }));
+    check_purchase_limits(purchase_limits, event_id, &purchased, &holds)?;
+
+    Ok(holds)
+  }).await
+}
+
+/// Compensation for `track_holds`. It is best effort since the entries will anyway expire.
+pub async fn untrack_holds(store: &Store, event_id: &str, buyer_uid: &str, ticket_nfts: &[&str]) {
+  let result = update_holds(store, event_id, buyer_uid, |mut holds, _| {
+    holds.retain(|hold| !ticket_nfts.contains(&hold.ticket_nft.as_str()));
+    Ok(holds)
+  }).await;
+
+  if let Err(error) = result {
+    println!("Failed to untrack the holds of buyer {} for event {}: {:?}", buyer_uid, event_id, error);
+  }
+}
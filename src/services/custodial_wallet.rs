@@ -0,0 +1,44 @@
+use chrono::Duration;
+use eyre::{Result, ContextCompat};
+use serde::{Serialize, Deserialize};
+use crate::utils::store::Store;
+
+#[derive(Serialize)]
+struct WalletRequest<'a> {
+  account_id: &'a str,
+}
+
+#[derive(Deserialize)]
+struct WalletResponse {
+  pubkey: String,
+}
+
+fn custodial_wallet_key(buyer_uid: &str) -> String {
+  format!("custodial_wallet:{}", buyer_uid)
+}
+
+/// Returns the custodial wallet of the buyer, creating one if it doesn't exist yet. The keys are managed by
+/// the key management service which returns the existing wallet of the account if there is one.
+pub async fn custodial_wallet(store: &Store, buyer_uid: &str) -> Result<String> {
+  let redis_key = custodial_wallet_key(buyer_uid);
+  let mut redis = store.redis_pool.connection().await?;
+
+  if let Ok(pubkey) = redis.get(&redis_key).await {
+    return Ok(pubkey)
+  }
+
+  let url = store.config.custodial_wallet_url.as_ref().context("custodial wallets are not enabled")?;
+  let wallet = reqwest::Client::new()
+  .post(format!("{}/wallets", url))
+  .json(&WalletRequest {account_id: buyer_uid})
+  .send()
+  .await?
+  .error_for_status()?
+  .json::<WalletResponse>()
+  .await?;
+
+  // A wallet never changes so the cache is only bounded to avoid keeping inactive buyers around
+  redis.set_ex(&redis_key, &wallet.pubkey, Duration::days(1).num_milliseconds() as usize).await?;
+
+  Ok(wallet.pubkey)
+}
@@ -0,0 +1,62 @@
+use std::{
+  future::Future,
+  time::{Duration as StdDuration, Instant},
+};
+use chrono::Duration;
+use eyre::{Result, Report};
+use solana_sdk::hash::hash;
+use crate::utils::store::Store;
+
+const SLOT_POLL_INTERVAL: StdDuration = StdDuration::from_millis(200);
+/// A waiting request holds one of the consumer's prefetched messages, so it gives up rather than block the requests
+/// of other events behind it
+const SLOT_WAIT_TIMEOUT: StdDuration = StdDuration::from_secs(5);
+
+/// The hash must be the same on every instance so that they all agree on the partition of an event
+fn event_partition(event_id: &str, partitions: usize) -> usize {
+  let mut bytes = [0; 8];
+  bytes.copy_from_slice(&hash(event_id.as_bytes()).to_bytes()[..8]);
+
+  (u64::from_le_bytes(bytes) % partitions as u64) as usize
+}
+
+fn partition_slot(partition: usize, slot: usize) -> Vec<u8> {
+  format!("lock:event_partition:{}:{}", partition, slot).into_bytes()
+}
+
+/// Runs `process` once one of the slots of the event's partition is free. Each slot is a RedLock lock so that the
+/// limit applies across all the instances. This bounds the parallelism of an event but waiting requests are not
+/// served in arrival order; that would need a queue per partition. If no slot frees up within `SLOT_WAIT_TIMEOUT`
+/// an error is returned so that the message goes through the retry queue.
+/// `slot_duration` must cover the processing since the slot is freed when it expires.
+pub async fn with_partition_slot<F, Fut>(store: &Store, event_id: &str, slot_duration: Duration, process: F) -> Result<()>
+where
+  F: FnOnce() -> Fut,
+  Fut: Future<Output = Result<()>>,
+{
+  let partitions = store.config.event_partitions;
+  if partitions == 0 {
+    return process().await
+  }
+
+  let partition = event_partition(event_id, partitions);
+  let started_at = Instant::now();
+  let lock = 'acquire: loop {
+    for slot in 0..store.config.event_partition_concurrency {
+      if let Ok(lock) = store.redlock.lock(&partition_slot(partition, slot), slot_duration.num_milliseconds() as usize).await {
+        break 'acquire lock
+      }
+    }
+
+    if started_at.elapsed() >= SLOT_WAIT_TIMEOUT {
+      return Err(Report::msg(format!("No slot of partition {} is free for event {}", partition, event_id)))
+    }
+
+    tokio::time::sleep(SLOT_POLL_INTERVAL).await;
+  };
+
+  let result = process().await;
+  store.redlock.unlock(lock).await;
+
+  result
+}
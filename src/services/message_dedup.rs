@@ -0,0 +1,65 @@
+use chrono::Duration;
+use eyre::Result;
+use lapin::message::Delivery;
+use uuid::Uuid;
+use crate::{
+  models::envelope::Header,
+  queue::payment_producer::Reply,
+  services::outbox::read_reply,
+  utils::store::Store,
+};
+
+/// How long the reply of a processed message is kept. It outlives the reservations so that a redelivery never
+/// reserves the tickets again once the buyer has been sent a payment.
+const PROCESSED_MESSAGE_TTL: i64 = 15; // 15 minutes
+
+/// Enveloped messages are identified by their id and legacy ones by the AMQP message id if the producer set one.
+/// Messages with neither get a random key so they are never deduplicated. Their content can't identify them since
+/// a buyer can legitimately send the same request twice.
+pub fn message_key(header: &Header, delivery: &Delivery) -> String {
+  let message_id = if !header.message_id.is_empty() {
+    header.message_id.clone()
+  } else if let Some(message_id) = delivery.properties.message_id() {
+    message_id.as_str().to_string()
+  } else {
+    Uuid::new_v4().to_string()
+  };
+
+  format!("create_payment:{}", message_id)
+}
+
+/// RedLock keeps the lock under the resource name so it must differ from the key the reply is saved under
+pub fn message_lock(message_key: &str) -> Vec<u8> {
+  format!("lock:{}", message_key).into_bytes()
+}
+
+/// Returns the reply that was published for a message that has already been processed
+pub async fn processed_reply(store: &Store, message_key: &str) -> Result<Option<Reply>> {
+  let mut redis = store.redis_pool.connection().await?;
+
+  let outbox_id = match redis.get(message_key).await {
+    Ok(outbox_id) => outbox_id.parse::<i64>()?,
+    // `get` fails both for missing keys and for connection errors. Only the former means the message is new;
+    // for the latter processing it could check out the same tickets twice.
+    Err(error) => return match redis.exists(message_key).await? {
+      false => Ok(None),
+      true => Err(error.into()),
+    },
+  };
+
+  Ok(Some(read_reply(store, outbox_id).await?))
+}
+
+/// Records the outbox row holding the reply of a message. This must happen before the reply is published so that
+/// a crash before the ack doesn't lead to a second checkout. Only the id is kept in Redis since the reply contains
+/// the client secret, which is encrypted in the outbox.
+pub async fn save_reply(store: &Store, message_key: &str, outbox_id: i64) -> Result<()> {
+  let mut redis = store.redis_pool.connection().await?;
+  redis.set_ex(
+    message_key,
+    &outbox_id.to_string(),
+    Duration::minutes(PROCESSED_MESSAGE_TTL).num_milliseconds() as usize,
+  ).await?;
+
+  Ok(())
+}
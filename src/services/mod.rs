@@ -1,3 +1,14 @@
 pub mod stripe;
 pub mod ticket_purchase;
+pub mod ticket_hold;
+pub mod buyer_limits;
+pub mod message_dedup;
+pub mod event_partition;
+pub mod outbox;
+pub mod recipient;
+pub mod custodial_wallet;
 pub mod price_feed;
+pub mod seat_allocation;
+pub mod tx_sender;
+pub mod program_errors;
+pub mod signer;
@@ -0,0 +1,183 @@
+use aes_gcm_siv::{
+  Aes256GcmSiv, Nonce,
+  aead::{Aead, NewAead},
+};
+use borsh::{BorshSerialize, BorshDeserialize};
+use eyre::{Result, Report};
+use chrono::Duration;
+use diesel::{
+  QueryableByName, sql_query,
+  sql_types::{BigInt, Binary, Double, Nullable, Text},
+};
+use diesel_async::RunQueryDsl;
+use crate::{
+  models::payment_intent::PaymentSecret,
+  queue::payment_producer::Reply,
+  utils::store::Store,
+};
+
+const NONCE_SIZE: usize = 12;
+/// Rows younger than this are left to the consumer that created them which publishes them straight away
+const RELAY_DELAY_SECS: f64 = 10.0;
+const RELAY_BATCH_SIZE: i64 = 100;
+const RELAY_LOCK_DURATION: i64 = 30; // 30 seconds
+
+#[derive(QueryableByName)]
+struct OutboxId {
+  #[diesel(sql_type = BigInt)]
+  id: i64,
+}
+
+#[derive(QueryableByName)]
+struct Payload {
+  #[diesel(sql_type = Binary)]
+  payload: Vec<u8>,
+}
+
+#[derive(QueryableByName)]
+struct PendingReply {
+  #[diesel(sql_type = BigInt)]
+  id: i64,
+  #[diesel(sql_type = Binary)]
+  payload: Vec<u8>,
+}
+
+fn relay_lock(outbox_id: i64) -> Vec<u8> {
+  format!("lock:payment_outbox:{}", outbox_id).into_bytes()
+}
+
+/// Returns the nonce followed by the ciphertext
+fn encrypt(key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+  let cipher = Aes256GcmSiv::new_from_slice(key).map_err(|_| Report::msg("Invalid outbox encryption key"))?;
+  let nonce = rand::random::<[u8; NONCE_SIZE]>();
+  let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), plaintext).map_err(|_| Report::msg("Failed to encrypt"))?;
+
+  Ok([&nonce[..], &ciphertext].concat())
+}
+
+fn decrypt(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+  if data.len() < NONCE_SIZE {
+    return Err(Report::msg("Invalid ciphertext"))
+  }
+
+  let cipher = Aes256GcmSiv::new_from_slice(key).map_err(|_| Report::msg("Invalid outbox encryption key"))?;
+  let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+
+  cipher.decrypt(Nonce::from_slice(nonce), ciphertext).map_err(|_| Report::msg("Failed to decrypt"))
+}
+
+pub struct PaymentAttempt<'a> {
+  pub message_key: &'a str,
+  pub ws_session_id: &'a str,
+  pub buyer_uid: &'a str,
+  pub event_id: &'a str,
+  pub payment_secret: &'a PaymentSecret,
+}
+
+/// Stores the payment attempt and its reply in a single statement so that one is never written without the other.
+/// Returns the id of the outbox row.
+pub async fn record_payment(store: &Store, attempt: PaymentAttempt<'_>, reply: &Reply) -> Result<i64> {
+  let key = &store.config.outbox_encryption_key;
+  let (client_secret, error) = match attempt.payment_secret {
+    PaymentSecret::Ok(client_secret) => (Some(encrypt(key, client_secret.as_bytes())?), None),
+    PaymentSecret::Err(error) => (None, Some(error.clone())),
+  };
+  let payload = encrypt(key, &reply.try_to_vec()?)?;
+
+  let mut connection = store.sql_pool.get().await?;
+  let outbox = sql_query(
+    "WITH attempt AS (
+      INSERT INTO payment_attempts (message_key, ws_session_id, buyer_uid, event_id, client_secret, error)
+      VALUES ($1, $2, $3, $4, $5, $6) RETURNING id
+    )
+    INSERT INTO payment_outbox (payment_attempt_id, payload) SELECT id, $7 FROM attempt RETURNING id"
+  )
+  .bind::<Text, _>(attempt.message_key)
+  .bind::<Text, _>(attempt.ws_session_id)
+  .bind::<Text, _>(attempt.buyer_uid)
+  .bind::<Text, _>(attempt.event_id)
+  .bind::<Nullable<Binary>, _>(client_secret)
+  .bind::<Nullable<Text>, _>(error)
+  .bind::<Binary, _>(payload)
+  .get_result::<OutboxId>(&mut *connection)
+  .await?;
+
+  Ok(outbox.id)
+}
+
+pub async fn mark_published(store: &Store, outbox_id: i64) -> Result<()> {
+  let mut connection = store.sql_pool.get().await?;
+  sql_query("UPDATE payment_outbox SET published_at = NOW() WHERE id = $1")
+  .bind::<BigInt, _>(outbox_id)
+  .execute(&mut *connection)
+  .await?;
+
+  Ok(())
+}
+
+async fn record_failure(store: &Store, outbox_id: i64, error: String) -> Result<()> {
+  let mut connection = store.sql_pool.get().await?;
+  sql_query("UPDATE payment_outbox SET attempts = attempts + 1, last_error = $2 WHERE id = $1")
+  .bind::<BigInt, _>(outbox_id)
+  .bind::<Text, _>(error)
+  .execute(&mut *connection)
+  .await?;
+
+  Ok(())
+}
+
+fn decode_reply(store: &Store, payload: &[u8]) -> Result<Reply> {
+  Ok(Reply::try_from_slice(&decrypt(&store.config.outbox_encryption_key, payload)?)?)
+}
+
+pub async fn read_reply(store: &Store, outbox_id: i64) -> Result<Reply> {
+  let mut connection = store.sql_pool.get().await?;
+  let outbox = sql_query("SELECT payload FROM payment_outbox WHERE id = $1")
+  .bind::<BigInt, _>(outbox_id)
+  .get_result::<Payload>(&mut *connection)
+  .await?;
+
+  decode_reply(store, &outbox.payload)
+}
+
+async fn relay(store: &Store, payload: &[u8]) -> Result<()> {
+  let reply = decode_reply(store, payload)?;
+  store.payment_producer.new_payment(&reply).await
+}
+
+/// Publishes the replies that haven't been published yet. Returns the number of published replies.
+pub async fn relay_pending(store: &Store) -> Result<usize> {
+  let pending = {
+    let mut connection = store.sql_pool.get().await?;
+    sql_query(
+      "SELECT id, payload FROM payment_outbox
+      WHERE published_at IS NULL AND created_at < NOW() - make_interval(secs => $1)
+      ORDER BY id LIMIT $2"
+    )
+    .bind::<Double, _>(RELAY_DELAY_SECS)
+    .bind::<BigInt, _>(RELAY_BATCH_SIZE)
+    .load::<PendingReply>(&mut *connection)
+    .await?
+  };
+  let mut published = 0;
+
+  for outbox in pending {
+    // Many instances can run the relay. A row that is still published twice is dropped by the consumers.
+    let lock = match store.redlock.lock(
+      &relay_lock(outbox.id),
+      Duration::seconds(RELAY_LOCK_DURATION).num_milliseconds() as usize,
+    ).await {
+      Ok(lock) => lock,
+      Err(_) => continue,
+    };
+
+    let result = match relay(store, &outbox.payload).await {
+      Ok(_) => mark_published(store, outbox.id).await.map(|_| published += 1),
+      Err(error) => record_failure(store, outbox.id, error.to_string()).await,
+    };
+    store.redlock.unlock(lock).await;
+    result?;
+  }
+
+  Ok(published)
+}
@@ -0,0 +1,146 @@
+use solana_sdk::{
+  instruction::{Instruction, InstructionError},
+  transaction::TransactionError,
+};
+use program_artifacts::{
+  ticket_sale::{self, error::ErrorCode as TicketSaleError},
+  secondary_market::{self, error::ErrorCode as SecondaryMarketError},
+};
+use crate::utils::error::CheckoutError;
+
+/// Extracts the error code name from the Anchor logs i.e.
+/// `Program log: AnchorError thrown in src/lib.rs:10. Error Code: SeatAlreadyMinted. Error Number: 6000. Error Message: ...`
+fn anchor_error_code(logs: &[String]) -> Option<String> {
+  logs.iter()
+  .filter(|log| log.contains("AnchorError"))
+  .find_map(|log| {
+    let (_, rest) = log.split_once("Error Code: ")?;
+    let (error_code, _) = rest.split_once('.')?;
+
+    Some(error_code.to_string())
+  })
+}
+
+/// Anchor offsets the codes of the program defined errors by this amount
+const ERROR_CODE_OFFSET: u32 = 6000;
+/// Anchor framework error returned when an account the instruction expects doesn't exist
+const ACCOUNT_NOT_INITIALIZED: u32 = 3012;
+
+const fn custom_error(variant: u32) -> u32 {
+  ERROR_CODE_OFFSET + variant
+}
+
+const SEAT_ALREADY_MINTED: u32 = custom_error(TicketSaleError::SeatAlreadyMinted as u32);
+const INVALID_SEAT_INDEX: u32 = custom_error(TicketSaleError::InvalidSeatIndex as u32);
+const SEAT_RESERVED: u32 = custom_error(TicketSaleError::SeatReserved as u32);
+const SALE_NOT_STARTED: u32 = custom_error(TicketSaleError::SaleNotStarted as u32);
+const SALE_ENDED: u32 = custom_error(TicketSaleError::SaleEnded as u32);
+const SELL_LISTING_RESERVED: u32 = custom_error(SecondaryMarketError::SellListingReserved as u32);
+
+fn ticket_sale_error(code: u32) -> Option<CheckoutError> {
+  match code {
+    SEAT_ALREADY_MINTED | INVALID_SEAT_INDEX => Some(CheckoutError::TicketUnavailable),
+    SEAT_RESERVED => Some(CheckoutError::TicketReserved),
+    SALE_NOT_STARTED | SALE_ENDED => Some(CheckoutError::SaleNotActive),
+    _ => None,
+  }
+}
+
+fn secondary_market_error(code: u32) -> Option<CheckoutError> {
+  match code {
+    // The program closes the sell listing account once it is filled
+    ACCOUNT_NOT_INITIALIZED => Some(CheckoutError::SellListingUnavailable),
+    SELL_LISTING_RESERVED => Some(CheckoutError::TicketReserved),
+    _ => None,
+  }
+}
+
+/// Maps a failed transaction to a checkout error if the failure comes from one of the Ticketland programs.
+/// Such failures are deterministic and retrying the transaction will not help. Any other error e.g. an expired
+/// blockhash is transient and `None` is returned. The logs are optional and only used to name the errors we don't map.
+pub fn decode_program_error(ixs: &[Instruction], error: &TransactionError, logs: &[String]) -> Option<CheckoutError> {
+  let (ix_index, code) = match error {
+    TransactionError::InstructionError(ix_index, InstructionError::Custom(code)) => (*ix_index as usize, *code),
+    _ => return None,
+  };
+
+  let program_id = ixs.get(ix_index)?.program_id;
+  let (program, known_error) = if program_id == ticket_sale::program_id() {
+    ("ticket_sale", ticket_sale_error(code))
+  } else if program_id == secondary_market::program_id() {
+    ("secondary_market", secondary_market_error(code))
+  } else {
+    return None
+  };
+
+  Some(known_error.unwrap_or(CheckoutError::ProgramError {
+    program: program.to_string(),
+    error: anchor_error_code(logs).unwrap_or(code.to_string()),
+  }))
+}
+
+#[cfg(test)]
+mod tests {
+  use solana_sdk::pubkey::Pubkey;
+  use super::*;
+
+  fn program_error(program_id: Pubkey, code: u32) -> Option<CheckoutError> {
+    let ixs = [Instruction::new_with_bytes(program_id, &[], vec![])];
+    let error = TransactionError::InstructionError(0, InstructionError::Custom(code));
+
+    decode_program_error(&ixs, &error, &[])
+  }
+
+  #[test]
+  fn maps_seat_already_minted() {
+    let error = program_error(ticket_sale::program_id(), SEAT_ALREADY_MINTED);
+    assert!(matches!(error, Some(CheckoutError::TicketUnavailable)));
+  }
+
+  #[test]
+  fn maps_invalid_seat_index() {
+    let error = program_error(ticket_sale::program_id(), INVALID_SEAT_INDEX);
+    assert!(matches!(error, Some(CheckoutError::TicketUnavailable)));
+  }
+
+  #[test]
+  fn maps_seat_reserved() {
+    let error = program_error(ticket_sale::program_id(), SEAT_RESERVED);
+    assert!(matches!(error, Some(CheckoutError::TicketReserved)));
+  }
+
+  #[test]
+  fn maps_sale_not_started() {
+    let error = program_error(ticket_sale::program_id(), SALE_NOT_STARTED);
+    assert!(matches!(error, Some(CheckoutError::SaleNotActive)));
+  }
+
+  #[test]
+  fn maps_sale_ended() {
+    let error = program_error(ticket_sale::program_id(), SALE_ENDED);
+    assert!(matches!(error, Some(CheckoutError::SaleNotActive)));
+  }
+
+  #[test]
+  fn maps_closed_sell_listing() {
+    let error = program_error(secondary_market::program_id(), ACCOUNT_NOT_INITIALIZED);
+    assert!(matches!(error, Some(CheckoutError::SellListingUnavailable)));
+  }
+
+  #[test]
+  fn maps_sell_listing_reserved() {
+    let error = program_error(secondary_market::program_id(), SELL_LISTING_RESERVED);
+    assert!(matches!(error, Some(CheckoutError::TicketReserved)));
+  }
+
+  #[test]
+  fn unmapped_program_error_keeps_the_code() {
+    let error = program_error(ticket_sale::program_id(), 1);
+    assert!(matches!(error, Some(CheckoutError::ProgramError { error, .. }) if error == "1"));
+  }
+
+  #[test]
+  fn ignores_errors_of_other_programs() {
+    assert!(program_error(Pubkey::new_unique(), SEAT_RESERVED).is_none());
+  }
+}
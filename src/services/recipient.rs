@@ -0,0 +1,79 @@
+use std::str::FromStr;
+use chrono::Utc;
+use eyre::Result;
+use solana_sdk::{
+  pubkey::Pubkey,
+  signature::Signature,
+};
+use crate::utils::{
+  store::Store,
+  error::CheckoutError,
+};
+
+/// The message a buyer signs with one of their linked wallets to have tickets sent to a different wallet.
+/// `timestamp` is the unix time in milliseconds at which it was signed.
+pub fn recipient_delegation_message(buyer_uid: &str, recipient: &str, timestamp: i64) -> String {
+  format!("ticketland:recipient:{}:{}:{}", buyer_uid, recipient, timestamp)
+}
+
+/// Signature over `recipient_delegation_message` along with the timestamp it was signed at
+pub struct RecipientSignature {
+  pub signature: String,
+  pub timestamp: i64,
+}
+
+async fn linked_wallets(store: &Store, buyer_uid: &str) -> Result<Vec<Pubkey>> {
+  let mut postgres = store.pg_pool.connection().await?;
+  let account = postgres.read_account_by_id(buyer_uid.to_string()).await?;
+
+  Ok(
+    account.pubkey.iter()
+    .filter_map(|pubkey| Pubkey::from_str(pubkey).ok())
+    .collect()
+  )
+}
+
+/// Makes sure tickets can only be sent to the buyer. The recipient must either be one of the wallets linked
+/// to the buyer account or the buyer must have signed `recipient_delegation_message` with one of them. Signatures
+/// older than `recipient_signature_max_age` are rejected so that a leaked one can't be replayed later on.
+pub async fn verify_recipient(
+  store: &Store,
+  buyer_uid: &str,
+  recipient: &str,
+  recipient_signature: Option<&RecipientSignature>,
+) -> Result<()> {
+  let recipient_pubkey = Pubkey::from_str(recipient)
+  .map_err(|_| CheckoutError::InvalidRecipient("invalid pubkey".to_string()))?;
+
+  // Tickets sent to an off-curve address e.g. a PDA could never be used by the buyer
+  if !recipient_pubkey.is_on_curve() {
+    return Err(CheckoutError::InvalidRecipient("pubkey is not on curve".to_string()))?
+  }
+
+  let linked_wallets = linked_wallets(store, buyer_uid).await?;
+
+  if linked_wallets.contains(&recipient_pubkey) {
+    return Ok(())
+  }
+
+  let recipient_signature = recipient_signature
+  .ok_or(CheckoutError::InvalidRecipient("recipient is not linked to the buyer".to_string()))?;
+  let signature = Signature::from_str(&recipient_signature.signature)
+  .map_err(|_| CheckoutError::InvalidRecipient("invalid recipient signature".to_string()))?;
+
+  let age = Utc::now().timestamp_millis() - recipient_signature.timestamp;
+  if age.abs() > store.config.recipient_signature_max_age as i64 * 1000 {
+    return Err(CheckoutError::InvalidRecipient("recipient signature expired".to_string()))?
+  }
+
+  let message = recipient_delegation_message(buyer_uid, recipient, recipient_signature.timestamp);
+
+  let is_signed_by_buyer = linked_wallets.iter()
+  .any(|wallet| signature.verify(wallet.as_ref(), message.as_bytes()));
+
+  if !is_signed_by_buyer {
+    return Err(CheckoutError::InvalidRecipient("invalid recipient signature".to_string()))?
+  }
+
+  Ok(())
+}
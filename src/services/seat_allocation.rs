@@ -0,0 +1,231 @@
+use std::{
+  sync::Arc,
+  str::FromStr,
+  ops::Range,
+};
+use borsh::BorshDeserialize;
+use eyre::{Result, Report};
+use solana_sdk::pubkey::Pubkey;
+use ticketland_api::services::ticket_availability::get_next_seat_index;
+use ticketland_event_handler::services::ticket_purchase::pending_ticket_key;
+use solana_web3_rust::utils::pubkey_from_str;
+use program_artifacts::{
+  ticket_sale::{
+    self,
+    account_data::SeatReservation,
+  },
+  ticket_nft::pda as ticket_nft_pda,
+  event_registry::account_data::EventId,
+};
+use crate::{
+  models::create_payment::PrimaryCartItem,
+  utils::store::Store,
+};
+
+/// The max number of seats we will inspect, starting from the next available seat, when looking
+/// for a group of seats. The seats are fetched in batches so this bounds the number of RPC calls per allocation.
+const MAX_SCANNED_SEATS: u32 = 100;
+/// `getMultipleAccounts` accepts up to 100 accounts and each seat needs two of them
+const SCAN_BATCH_SIZE: u32 = 50;
+
+/// Picks `quantity` seats out of the available ones so that the distance between the first and the last
+/// seat is minimal. If there is a contiguous block it will be picked, otherwise this is a best-effort grouping.
+/// Note that `available_seats` must be sorted.
+pub fn group_seats(available_seats: &[u32], quantity: usize) -> Option<Vec<u32>> {
+  if quantity == 0 || available_seats.len() < quantity {
+    return None
+  }
+
+  let best_start = (0..=available_seats.len() - quantity)
+  .min_by_key(|start| available_seats[start + quantity - 1] - available_seats[*start])?;
+
+  Some(available_seats[best_start..best_start + quantity].to_vec())
+}
+
+/// Groups the lines of a cart by ticket type, in the order the ticket types first appear. The seats of a ticket
+/// type must be allocated once for all its lines; allocating per line would give every line the same seats.
+pub fn group_by_ticket_type(items: &[PrimaryCartItem]) -> Vec<(u8, Vec<&PrimaryCartItem>)> {
+  let mut groups: Vec<(u8, Vec<&PrimaryCartItem>)> = vec![];
+
+  for item in items {
+    match groups.iter_mut().find(|(ticket_type_index, _)| *ticket_type_index == item.ticket_type_index) {
+      Some((_, group)) => group.push(item),
+      None => groups.push((item.ticket_type_index, vec![item])),
+    }
+  }
+
+  groups
+}
+
+/// Hands out the seats allocated for a ticket type to its lines in order, `quantity` seats per line
+pub fn assign_seats<'a>(items: &[&'a PrimaryCartItem], seat_indexes: &[u32]) -> Vec<(&'a PrimaryCartItem, u32)> {
+  items.iter()
+  .flat_map(|item| (0..item.quantity).map(move |_| *item))
+  .zip(seat_indexes.iter().copied())
+  .collect()
+}
+
+/// Returns the seats in `seat_indexes` that are available. The ticket nft and seat reservation accounts of all the
+/// seats are fetched in a single `getMultipleAccounts` call.
+async fn available_seats_in_batch(
+  store: &Store,
+  sale: &Pubkey,
+  event_id: &str,
+  ticket_type_index: u8,
+  recipients: &[Pubkey],
+  seat_indexes: Range<u32>,
+) -> Result<Vec<u32>> {
+  let seats = seat_indexes
+  .map(|seat_index| {
+    let ticket_nft = ticket_nft_pda::ticket_nft(
+      &store.config.ticket_nft_state,
+      seat_index,
+      &EventId(event_id.to_string()).val(),
+      ticket_type_index,
+    ).0;
+    let seat_reservation = ticket_sale::pda::seat_reservation(sale, seat_index, &seat_index.to_string()).0;
+
+    (seat_index, ticket_nft, seat_reservation)
+  })
+  .collect::<Vec<_>>();
+
+  let addresses = seats.iter()
+  .flat_map(|(_, ticket_nft, seat_reservation)| [*ticket_nft, *seat_reservation])
+  .collect::<Vec<_>>();
+  let accounts = store.batch_rpc_client.get_multiple_accounts(&addresses).await?;
+  let latest_slot = store.rpc_client.get_slot().await?;
+  let mut redis = store.redis_pool.connection().await?;
+  let mut available_seats = vec![];
+
+  for ((seat_index, ticket_nft, _), accounts) in seats.into_iter().zip(accounts.chunks(2)) {
+    // The ticket nft account exists once the seat has been minted
+    if accounts[0].is_some() {
+      continue
+    }
+
+    if let Some(account) = &accounts[1] {
+      // Skip the Anchor discriminator
+      let seat_reservation = SeatReservation::deserialize(&mut &account.data[8..])?;
+
+      if latest_slot <= seat_reservation.valid_until && !recipients.contains(&seat_reservation.recipient) {
+        continue
+      }
+    }
+
+    if let Ok(_) = redis.get(&pending_ticket_key(event_id, &ticket_nft.to_string())).await {
+      continue
+    }
+
+    available_seats.push(seat_index);
+  }
+
+  Ok(available_seats)
+}
+
+/// Whether the seats picked by `group_seats` sit next to each other
+fn has_contiguous_block(available_seats: &[u32], quantity: usize) -> bool {
+  match group_seats(available_seats, quantity) {
+    Some(seats) => seats[quantity - 1] - seats[0] == quantity as u32 - 1,
+    None => false,
+  }
+}
+
+/// Allocates `quantity` seats of the given ticket type that sit next to each other. When there is no
+/// contiguous block the seats that are closest together are returned instead. Seats reserved for one of the
+/// `recipients` count as available.
+pub async fn allocate_seats(
+  store: Arc<Store>,
+  sale_account: &str,
+  event_id: &str,
+  ticket_type_index: u8,
+  recipients: &[&str],
+  quantity: usize,
+) -> Result<Vec<u32>> {
+  let first_seat = get_next_seat_index(
+    &store.pg_pool,
+    &store.redis_pool,
+    Arc::clone(&store.rpc_client),
+    store.config.ticket_sale_state,
+    &EventId(event_id.to_string()),
+    ticket_type_index
+  ).await?;
+
+  if quantity == 1 {
+    return Ok(vec![first_seat])
+  }
+
+  let mut postgres = store.pg_pool.connection().await?;
+  let sale = postgres.read_sale_by_account(sale_account.to_string()).await?;
+  let sale_pubkey = Pubkey::from_str(sale_account)?;
+  let recipients = recipients.iter()
+  .map(|recipient| Ok(pubkey_from_str(recipient)?))
+  .collect::<Result<Vec<_>>>()?;
+
+  // seat range is [seat_range_start, seat_range_end)
+  let last_seat = (sale.seat_range_end as u32).min(first_seat + MAX_SCANNED_SEATS);
+  let mut available_seats = vec![first_seat];
+  let mut next_seat = first_seat + 1;
+
+  // Stop as soon as we find a contiguous block
+  while next_seat < last_seat && !has_contiguous_block(&available_seats, quantity) {
+    let batch_end = (next_seat + SCAN_BATCH_SIZE).min(last_seat);
+    available_seats.extend(
+      available_seats_in_batch(&store, &sale_pubkey, event_id, ticket_type_index, &recipients, next_seat..batch_end).await?
+    );
+    next_seat = batch_end;
+  }
+
+  group_seats(&available_seats, quantity).ok_or(Report::msg("Ticket unavailable"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn cart_item(ticket_type_index: u8, quantity: u8, recipient: &str) -> PrimaryCartItem {
+    PrimaryCartItem {
+      sale_account: format!("sale_{}", ticket_type_index),
+      ticket_type_index,
+      quantity,
+      recipient: recipient.to_string(),
+    }
+  }
+
+  #[test]
+  fn groups_contiguous_block() {
+    assert_eq!(group_seats(&[3, 5, 6, 7, 9], 3), Some(vec![5, 6, 7]));
+  }
+
+  #[test]
+  fn groups_closest_seats_when_there_is_no_contiguous_block() {
+    assert_eq!(group_seats(&[1, 4, 6, 7, 12], 3), Some(vec![4, 6, 7]));
+  }
+
+  #[test]
+  fn does_not_group_too_few_seats() {
+    assert_eq!(group_seats(&[1, 2], 3), None);
+    assert_eq!(group_seats(&[1, 2], 0), None);
+  }
+
+  #[test]
+  fn lines_of_the_same_ticket_type_get_distinct_seats() {
+    let items = vec![
+      cart_item(0, 2, "recipient_a"),
+      cart_item(1, 1, "recipient_c"),
+      cart_item(0, 1, "recipient_b"),
+    ];
+
+    let groups = group_by_ticket_type(&items);
+    assert_eq!(groups.len(), 2);
+
+    let (ticket_type_index, lines) = &groups[0];
+    assert_eq!(*ticket_type_index, 0);
+    assert_eq!(lines.iter().map(|item| item.quantity as usize).sum::<usize>(), 3);
+
+    let seats = assign_seats(lines, &[7, 8, 9])
+    .into_iter()
+    .map(|(item, seat_index)| (item.recipient.as_str(), seat_index))
+    .collect::<Vec<_>>();
+    assert_eq!(seats, vec![("recipient_a", 7), ("recipient_a", 8), ("recipient_b", 9)]);
+  }
+}
@@ -0,0 +1,169 @@
+use async_trait::async_trait;
+use eyre::{Result, Report};
+use serde::{Serialize, Deserialize};
+use solana_sdk::{
+  pubkey::Pubkey,
+  hash::hash,
+  message::Message,
+  compute_budget,
+  signature::{Keypair, Signature, Signer},
+};
+use solana_web3_rust::utils::pubkey_from_str;
+use program_artifacts::{ticket_sale, secondary_market};
+use crate::utils::config::RemoteSignerConfig;
+
+/// Signs the operator transactions. The operator key can either live in this process or in a remote signing service.
+#[async_trait]
+pub trait OperatorSigner: Send + Sync {
+  fn pubkey(&self) -> Pubkey;
+
+  async fn sign_message(&self, message: &[u8]) -> Result<Signature>;
+}
+
+pub struct LocalSigner {
+  keypair: Keypair,
+}
+
+impl LocalSigner {
+  pub fn new(priv_key: &str) -> Self {
+    Self {
+      keypair: Keypair::from_base58_string(priv_key),
+    }
+  }
+}
+
+#[async_trait]
+impl OperatorSigner for LocalSigner {
+  fn pubkey(&self) -> Pubkey {
+    self.keypair.pubkey()
+  }
+
+  async fn sign_message(&self, message: &[u8]) -> Result<Signature> {
+    Ok(self.keypair.sign_message(message))
+  }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SignRequest {
+  pub pubkey: String,
+  /// base64 encoded serialized transaction message
+  pub message: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SignResponse {
+  pub signature: String,
+}
+
+/// Asks a remote signing service to sign the message with the key of the given pubkey. Requests carry the configured
+/// token as a bearer token. The service must reject requests without it and must enforce `check_signing_policy`
+/// itself: the policy check in this process doesn't protect the keys from anyone else who can reach the service.
+pub struct RemoteSigner {
+  client: reqwest::Client,
+  config: RemoteSignerConfig,
+  pubkey: Pubkey,
+}
+
+impl RemoteSigner {
+  pub fn new(config: RemoteSignerConfig, pubkey: Pubkey) -> Self {
+    Self {
+      client: reqwest::Client::new(),
+      config,
+      pubkey,
+    }
+  }
+}
+
+#[async_trait]
+impl OperatorSigner for RemoteSigner {
+  fn pubkey(&self) -> Pubkey {
+    self.pubkey
+  }
+
+  async fn sign_message(&self, message: &[u8]) -> Result<Signature> {
+    let response = self.client.post(format!("{}/sign", self.config.url))
+    .bearer_auth(&self.config.token)
+    .json(&SignRequest {
+      pubkey: self.pubkey.to_string(),
+      message: base64::encode(message),
+    })
+    .send()
+    .await?
+    .error_for_status()?
+    .json::<SignResponse>()
+    .await?;
+
+    let signature = response.signature.parse::<Signature>()?;
+
+    // Never trust the remote service blindly
+    if !signature.verify(self.pubkey.as_ref(), message) {
+      return Err(Report::msg("Remote signer returned an invalid signature"))
+    }
+
+    Ok(signature)
+  }
+}
+
+/// Anchor instruction discriminator i.e. the first 8 bytes of sha256("global:<ix_name>")
+fn ix_discriminator(ix_name: &str) -> [u8; 8] {
+  let mut discriminator = [0; 8];
+  discriminator.copy_from_slice(&hash(format!("global:{}", ix_name).as_bytes()).to_bytes()[..8]);
+
+  discriminator
+}
+
+/// Only the reservation instructions of the ticket_sale and secondary_market programs can be signed by the operator.
+/// Closing expired reservations is part of the same lifecycle so it's allowed too. Compute budget instructions are
+/// allowed since they are attached to every transaction.
+pub fn check_signing_policy(message: &Message) -> Result<()> {
+  let allowed_ixs = [
+    (ticket_sale::program_id(), ix_discriminator("reserve_seat")),
+    (ticket_sale::program_id(), ix_discriminator("close_seat_reservation")),
+    (secondary_market::program_id(), ix_discriminator("reserve_sell_listing")),
+    (secondary_market::program_id(), ix_discriminator("close_sell_listing_reservation")),
+  ];
+
+  for ix in &message.instructions {
+    let program_id = message.account_keys.get(ix.program_id_index as usize)
+    .ok_or(Report::msg("Invalid program id index"))?;
+
+    if *program_id == compute_budget::id() {
+      continue
+    }
+
+    let is_allowed = allowed_ixs.iter().any(|(allowed_program_id, discriminator)| {
+      program_id == allowed_program_id && ix.data.starts_with(discriminator)
+    });
+
+    if !is_allowed {
+      return Err(Report::msg(format!("Signing policy violation: instruction of program {} is not allowed", program_id)))
+    }
+  }
+
+  Ok(())
+}
+
+/// Fails if no operator is configured for the chosen signer, i.e. `REMOTE_SIGNER_URL` is set but `OPERATOR_PUBKEYS` is empty
+pub fn operator_signers(
+  remote_signer: Option<&RemoteSignerConfig>,
+  operator_priv_keys: &[String],
+  operator_pubkeys: &[String],
+) -> Result<Vec<Box<dyn OperatorSigner>>> {
+  let signers = match remote_signer {
+    Some(config) => operator_pubkeys.iter()
+    .map(|pubkey| Ok(Box::new(RemoteSigner::new(config.clone(), pubkey_from_str(pubkey)?)) as Box<dyn OperatorSigner>))
+    .collect::<Result<Vec<_>>>()?,
+    None => operator_priv_keys.iter()
+    .map(|priv_key| Box::new(LocalSigner::new(priv_key)) as Box<dyn OperatorSigner>)
+    .collect(),
+  };
+
+  if signers.is_empty() {
+    return Err(match remote_signer {
+      Some(_) => Report::msg("REMOTE_SIGNER_URL is set but OPERATOR_PUBKEYS is empty"),
+      None => Report::msg("OPERATOR_PRIV_KEYS is empty"),
+    })
+  }
+
+  Ok(signers)
+}
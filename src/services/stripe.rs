@@ -1,11 +1,9 @@
 use std::{
   sync::Arc,
-  future::Future,
-  pin::Pin, str::FromStr,
+  str::FromStr,
 };
 use chrono::{Duration, NaiveDateTime};
-use eyre::{Result, Report, ContextCompat};
-use solana_sdk::pubkey::Pubkey;
+use eyre::{Result, ContextCompat};
 use stripe::{
   Client, Customer, CreateCustomer,
   Currency, CreatePaymentIntent, Metadata, CreatePaymentIntentTransferData, PaymentIntent, CustomerId
@@ -14,138 +12,110 @@ use ticketland_core::{
   async_helpers::timeout,
 };
 use ticketland_data::models::stripe_customer::StripeCustomer;
-use ticketland_event_handler::{
-  services::ticket_purchase::pending_ticket_key,
+use crate::{
+  models::create_payment::CreatePayment,
+  utils::store::Store,
 };
-use program_artifacts::{
-  ticket_nft::pda as ticket_nft_pda,
-  secondary_market::pda,
-};
-use crate::utils::store::Store;
-use super::ticket_purchase::{
-  PrePurchaseChecksParams,
-  pre_primary_purchase_checks,
-  pre_secondary_purchase_checks,
-};
-
-type PrePurchaseCheck = Pin<Box<dyn Future<Output = Result<(i64, i64)>> + Send>>;
-
-pub async fn create_primary_sale_payment(
-  store: Arc<Store>,
-  buyer_uid: String,
-  sale_account: String,
-  event_id: String,
-  ticket_nft: String,
-  ticket_type_index: u8,
-  recipient: String,
-  seat_index: u32,
-  seat_name: String,
-) -> Result<String> {
-  let pre_purchase_check_params = PrePurchaseChecksParams::Primary {
-    store: Arc::clone(&store),
-    event_id: event_id.clone(),
-    seat_index: seat_index,
-    sale_account: sale_account.clone(),
-    ticket_nft: ticket_nft.clone(),
-  };
-
-  let payment_metadata = Some([
-    ("sale_type".to_string(), "primary".to_string()),
-    ("buyer_uid".to_string(), buyer_uid.clone()),
-    ("sale_account".to_string(), sale_account.clone()),
-    ("event_id".to_string(), event_id.clone()),
-    ("ticket_nft".to_string(), ticket_nft.clone()),
-    ("ticket_type_index".to_string(), ticket_type_index.to_string()),
-    ("recipient".to_string(), recipient.clone()),
-    ("seat_index".to_string(), seat_index.to_string()),
-    ("seat_name".to_string(), seat_name.clone()),
-  ].iter().cloned().collect());
-
-  create_payment(
-    store,
-    buyer_uid,
-    event_id,
-    ticket_nft,
-    Box::pin(pre_primary_purchase_checks(pre_purchase_check_params)),
-    payment_metadata,
-  ).await
-}
-
-pub async fn create_secondary_sale_payment(
-  store: Arc<Store>,
-  buyer_uid: String,
-  sale_account: String,
-  event_id: String,
-  ticket_nft: String,
-  ticket_type_index: u8,
-  recipient: String,
-) -> Result<String> {
-  let ticket_nft_pubkey = Pubkey::from_str(&ticket_nft)?;
-  let ticket_matadata = ticket_nft_pda::ticket_metadata(&store.config.ticket_nft_state, &ticket_nft_pubkey).0;
-  let sell_listing_account = pda::sell_listing(
-    &store.config.secondary_market_state,
-    &event_id,
-    &ticket_matadata,
-  ).0;
-
-  let pre_purchase_check_params = PrePurchaseChecksParams::Secondary {
-    store: Arc::clone(&store),
-    ticket_nft: ticket_nft.clone(),
-    sell_listing_account: sell_listing_account.to_string(),
-  };
-
-  let payment_metadata = Some([
-    ("sale_type".to_string(), "secondary".to_string()),
-    ("buyer_uid".to_string(), buyer_uid.clone()),
-    ("sale_account".to_string(), sale_account.clone()),
-    ("event_id".to_string(), event_id.clone()),
-    ("ticket_nft".to_string(), ticket_nft.clone()),
-    ("ticket_type_index".to_string(), ticket_type_index.to_string()),
-    ("recipient".to_string(), recipient.clone()),
-    ("sell_listing_account".to_string(), sell_listing_account.to_string()),
-  ].iter().cloned().collect());
-
-  create_payment(
-    store,
-    buyer_uid,
-    event_id,
-    ticket_nft,
-    Box::pin(pre_secondary_purchase_checks(pre_purchase_check_params)),
-    payment_metadata,
-  ).await
+use super::ticket_purchase::{CheckoutTicket, sell_listing_account};
+
+/// Builds the metadata attached to the PaymentIntent.
+///
+/// Single ticket messages keep the original primary/secondary format. Cart payments describe each ticket
+/// under the `ticket_{i}` key using the following format:
+///
+/// primary: `primary:{sale_account}:{ticket_nft}:{ticket_type_index}:{recipient}:{seat_index}:{seat_name}`
+/// secondary: `secondary:{sale_account}:{ticket_nft}:{ticket_type_index}:{recipient}:{sell_listing_account}`
+pub fn payment_metadata(store: &Store, msg: &CreatePayment, tickets: &[CheckoutTicket]) -> Result<Metadata> {
+  let buyer_uid = msg.buyer_uid().to_string();
+  let event_id = msg.event_id().to_string();
+
+  match (msg, tickets) {
+    (CreatePayment::Primary {..}, [CheckoutTicket::Primary {
+      sale_account,
+      ticket_nft,
+      ticket_type_index,
+      recipient,
+      seat_index,
+      seat_name,
+    }]) => Ok([
+      ("sale_type".to_string(), "primary".to_string()),
+      ("buyer_uid".to_string(), buyer_uid),
+      ("sale_account".to_string(), sale_account.clone()),
+      ("event_id".to_string(), event_id),
+      ("ticket_nft".to_string(), ticket_nft.clone()),
+      ("ticket_type_index".to_string(), ticket_type_index.to_string()),
+      ("recipient".to_string(), recipient.clone()),
+      ("seat_index".to_string(), seat_index.to_string()),
+      ("seat_name".to_string(), seat_name.clone()),
+    ].iter().cloned().collect()),
+    (CreatePayment::Secondary {..}, [CheckoutTicket::Secondary {
+      sale_account,
+      ticket_nft,
+      ticket_type_index,
+      recipient,
+    }]) => Ok([
+      ("sale_type".to_string(), "secondary".to_string()),
+      ("buyer_uid".to_string(), buyer_uid),
+      ("sale_account".to_string(), sale_account.clone()),
+      ("event_id".to_string(), event_id.clone()),
+      ("ticket_nft".to_string(), ticket_nft.clone()),
+      ("ticket_type_index".to_string(), ticket_type_index.to_string()),
+      ("recipient".to_string(), recipient.clone()),
+      ("sell_listing_account".to_string(), sell_listing_account(store, &event_id, ticket_nft)?.to_string()),
+    ].iter().cloned().collect()),
+    _ => {
+      let mut payment_metadata: Metadata = [
+        ("sale_type".to_string(), "cart".to_string()),
+        ("buyer_uid".to_string(), buyer_uid),
+        ("event_id".to_string(), event_id.clone()),
+        ("tickets".to_string(), tickets.len().to_string()),
+      ].iter().cloned().collect();
+
+      for (i, ticket) in tickets.iter().enumerate() {
+        let ticket_metadata = match ticket {
+          CheckoutTicket::Primary {
+            sale_account,
+            ticket_nft,
+            ticket_type_index,
+            recipient,
+            seat_index,
+            seat_name,
+          } => format!("primary:{}:{}:{}:{}:{}:{}", sale_account, ticket_nft, ticket_type_index, recipient, seat_index, seat_name),
+          CheckoutTicket::Secondary {
+            sale_account,
+            ticket_nft,
+            ticket_type_index,
+            recipient,
+          } => format!(
+            "secondary:{}:{}:{}:{}:{}",
+            sale_account,
+            ticket_nft,
+            ticket_type_index,
+            recipient,
+            sell_listing_account(store, &event_id, ticket_nft)?,
+          ),
+        };
+
+        payment_metadata.insert(format!("ticket_{}", i), ticket_metadata);
+      }
+
+      Ok(payment_metadata)
+    }
+  }
 }
 
 pub async fn create_payment(
   store: Arc<Store>,
   buyer_uid: String,
   event_id: String,
-  ticket_nft: String,
-  pre_purchase_checks: PrePurchaseCheck,
+  price: i64,
+  fee: i64,
   payment_metadata: Option<Metadata>,
 ) -> Result<String> {
-  // There are 5 async calls in this function. Each call will have a time out attached. The total timout is 13 seconds thus
-  // this lock will be valid until all calls have successfully processed or until one has a timeout at which point no link is
-  // returned to the user and thus the Scenario #3 we describe in the technical documentation will not pose an issue.
-  let lock = store.redlock.lock(ticket_nft.as_bytes(), Duration::seconds(15).num_milliseconds() as usize).await?;
-  
-  // Check if the ticket_nft key is in Redis; If so then the ticket is not available
-  // This can happen when someone tries to create a payment session straigth after someone else
-  // has already purchased or is in the middle of payment or waiting for the service to send the
-  // mint tx to the blockchain.
-  let redis_key = pending_ticket_key(&event_id, &ticket_nft);
-  {
-    let mut redis = store.redis_pool.connection().await?;
-    if let Ok(_) = redis.get(&redis_key).await {
-      return Err(Report::msg("Ticket not available"))
-    }
-  }
-
-  let (price, fee) = timeout(
-    Duration::seconds(5).num_milliseconds() as u64,
-    pre_purchase_checks,
-  ).await??;
-
-  let client = Client::new(store.config.stripe_key.clone());
+  let client = match &store.config.stripe_api_url {
+    Some(url) => Client::from_url(url.as_str(), store.config.stripe_key.clone()),
+    None => Client::new(store.config.stripe_key.clone()),
+  };
   let mut postgres = store.pg_pool.connection().await?;
   let account = postgres.read_account_by_id(buyer_uid.clone()).await?;
 
@@ -194,20 +164,6 @@ pub async fn create_payment(
     ).await??
   };
 
-  // Store ticket nft in Redis to mark it unavailable
-  // Add ttl that last one minute longer than the payment duration. This is to avoid some weird
-  // race conditions i.e. user checkouts the last second, the entry is removed from redis and another
-  // user calls this function at the same time at which point the ticket will not be minted nor the record
-  // will be in Redis because it expired and because the payment webhook has not be called yet to insert the
-  // entry again into Redis.
-  let mut redis = store.redis_pool.connection().await?;
-  timeout(
-    Duration::seconds(2).num_milliseconds() as u64,
-    redis.set_ex(&redis_key, &"1", Duration::minutes(6).num_milliseconds() as usize),
-  ).await??;
-
-  store.redlock.unlock(lock).await;
-
   let payment_secret = payment_intent.client_secret.context("payment secret not set")?;
   Ok(payment_secret)
 }
@@ -0,0 +1,102 @@
+use chrono::Duration;
+use eyre::Result;
+use ticketland_core::async_helpers::timeout;
+use ticketland_event_handler::services::ticket_purchase::pending_ticket_key;
+use crate::utils::{
+  store::Store,
+  error::CheckoutError,
+};
+
+/// How long a ticket stays pending. It lasts one minute longer than the payment duration, see `set_pending_keys`.
+pub const HOLD_DURATION: i64 = 6; // 6 minutes
+
+/// Marks the given tickets as pending in Redis so that nobody else can start a checkout for them.
+/// Either all tickets are held or none. Tickets already held by `owner` can be held again so that a retried
+/// checkout doesn't fail on its own holds.
+pub async fn hold_tickets(store: &Store, event_id: &str, ticket_nfts: &[&str], owner: &str) -> Result<()> {
+  // The lock guards the check and set below. The hold itself is the pending key in Redis so the
+  // lock can be released as soon as the keys are set.
+  let mut locks = Vec::with_capacity(ticket_nfts.len());
+  let mut result = Ok(());
+
+  for ticket_nft in ticket_nfts {
+    match store.redlock.lock(ticket_nft.as_bytes(), Duration::seconds(5).num_milliseconds() as usize).await {
+      Ok(lock) => locks.push(lock),
+      Err(error) => {
+        // The locks acquired so far are released below
+        result = Err(error.into());
+        break
+      }
+    }
+  }
+
+  if result.is_ok() {
+    result = set_pending_keys(store, event_id, ticket_nfts, owner).await;
+  }
+
+  for lock in locks {
+    store.redlock.unlock(lock).await;
+  }
+
+  result
+}
+
+async fn set_pending_keys(store: &Store, event_id: &str, ticket_nfts: &[&str], owner: &str) -> Result<()> {
+  let redis_keys = ticket_nfts.iter()
+  .map(|ticket_nft| pending_ticket_key(event_id, ticket_nft))
+  .collect::<Vec<_>>();
+  let mut redis = store.redis_pool.connection().await?;
+
+  // Check if the ticket_nft key is in Redis; If so then the ticket is not available
+  // This can happen when someone tries to create a payment session straigth after someone else
+  // has already purchased or is in the middle of payment or waiting for the service to send the
+  // mint tx to the blockchain.
+  for redis_key in &redis_keys {
+    match redis.get(redis_key).await {
+      Ok(holder) if holder != owner => return Err(CheckoutError::TicketUnavailable)?,
+      _ => {},
+    }
+  }
+
+  // Store ticket nft in Redis to mark it unavailable
+  // Add ttl that last one minute longer than the payment duration. This is to avoid some weird
+  // race conditions i.e. user checkouts the last second, the entry is removed from redis and another
+  // user calls this function at the same time at which point the ticket will not be minted nor the record
+  // will be in Redis because it expired and because the payment webhook has not be called yet to insert the
+  // entry again into Redis.
+  for (i, redis_key) in redis_keys.iter().enumerate() {
+    let result: Result<()> = async {
+      timeout(
+        Duration::seconds(2).num_milliseconds() as u64,
+        redis.set_ex(redis_key, &owner, Duration::minutes(HOLD_DURATION).num_milliseconds() as usize),
+      ).await??;
+
+      Ok(())
+    }.await;
+
+    if let Err(error) = result {
+      // Undo the keys that have already been set
+      release_tickets(store, event_id, &ticket_nfts[..i]).await;
+      return Err(error)
+    }
+  }
+
+  Ok(())
+}
+
+/// Compensation for `hold_tickets`. It is best effort since the keys will anyway expire.
+pub async fn release_tickets(store: &Store, event_id: &str, ticket_nfts: &[&str]) {
+  let mut redis = match store.redis_pool.connection().await {
+    Ok(redis) => redis,
+    Err(error) => {
+      println!("Failed to release tickets for event {}: {:?}", event_id, error);
+      return
+    }
+  };
+
+  for ticket_nft in ticket_nfts {
+    if let Err(error) = redis.delete(&pending_ticket_key(event_id, ticket_nft)).await {
+      println!("Failed to release ticket_nft {} for event {}: {:?}", ticket_nft, event_id, error);
+    }
+  }
+}
@@ -1,21 +1,35 @@
 use std::{
   sync::Arc,
   str::FromStr,
+  future::Future,
+  pin::Pin,
 };
+use chrono::Duration;
 use eyre::{Result, Report};
+use futures::future::try_join_all;
 use program_artifacts::{
   ticket_nft::pda,
+  secondary_market::{
+    self,
+    account_data::SellListing,
+  },
   event_registry::account_data::EventId,
 };
+use ticketland_core::async_helpers::timeout;
 use ticketland_data::models::sale::SaleType;
 use solana_sdk::{
   pubkey::Pubkey,
-  commitment_config::CommitmentConfig,
+  native_token::LAMPORTS_PER_SOL,
+};
+use crate::utils::{
+  store::Store,
+  error::CheckoutError,
 };
-use crate::utils::store::Store;
 
 use super::price_feed::get_sol_price;
 
+type PrePurchaseCheck = Pin<Box<dyn Future<Output = Result<(i64, i64)>> + Send>>;
+
 // 1 unit in Stripe is 100
 const STRIPE_UNIT: i64 = 100;
 
@@ -51,6 +65,76 @@ pub async fn calculate_price_and_fees(
   Ok((ticket_price as i64, total_fees as i64))
 }
 
+/// A ticket that is part of a checkout. A checkout of a cart will have many of them
+pub enum CheckoutTicket {
+  Primary {
+    sale_account: String,
+    ticket_nft: String,
+    ticket_type_index: u8,
+    recipient: String,
+    seat_index: u32,
+    seat_name: String,
+  },
+  Secondary {
+    sale_account: String,
+    ticket_nft: String,
+    ticket_type_index: u8,
+    recipient: String,
+  },
+}
+
+impl CheckoutTicket {
+  pub fn ticket_nft(&self) -> &str {
+    match self {
+      CheckoutTicket::Primary {ticket_nft, ..} => ticket_nft,
+      CheckoutTicket::Secondary {ticket_nft, ..} => ticket_nft,
+    }
+  }
+
+  pub fn ticket_type_index(&self) -> u8 {
+    match self {
+      CheckoutTicket::Primary {ticket_type_index, ..} => *ticket_type_index,
+      CheckoutTicket::Secondary {ticket_type_index, ..} => *ticket_type_index,
+    }
+  }
+}
+
+pub fn sell_listing_account(store: &Store, event_id: &str, ticket_nft: &str) -> Result<Pubkey> {
+  let ticket_nft_pubkey = Pubkey::from_str(ticket_nft)?;
+  let ticket_matadata = pda::ticket_metadata(&store.config.ticket_nft_state, &ticket_nft_pubkey).0;
+
+  Ok(
+    secondary_market::pda::sell_listing(
+      &store.config.secondary_market_state,
+      event_id,
+      &ticket_matadata,
+    ).0
+  )
+}
+
+/// Checks whether the account exists at the read commitment. `unavailable_if_exists` tells which result makes
+/// the ticket unavailable. That result is double checked at the stronger commitment when one is configured so
+/// that we don't turn buyers away because of a state that gets rolled back.
+pub async fn account_exists(store: &Store, account: &Pubkey, unavailable_if_exists: bool) -> Result<bool> {
+  let commitments = &store.config.commitments;
+  let exists = store.rpc_client.account_exists(account, commitments.read).await?;
+
+  match commitments.unavailable_double_check {
+    Some(commitment) if exists == unavailable_if_exists => store.rpc_client.account_exists(account, commitment).await,
+    _ => Ok(exists),
+  }
+}
+
+/// Converts a network fee paid by the operator e.g. for reserving a seat into Stripe units
+pub async fn lamports_to_stripe_unit(store: Arc<Store>, lamports: u64) -> Result<i64> {
+  if lamports == 0 {
+    return Ok(0)
+  }
+
+  let sol_price = get_sol_price(store).await?;
+
+  Ok((lamports as i64 * sol_price) / LAMPORTS_PER_SOL as i64)
+}
 
 pub enum PrePurchaseChecksParams {
   Primary {
@@ -93,6 +177,43 @@ impl PrePurchaseChecksParams {
   }
 }
 
+/// Runs the pre purchase checks of all tickets and returns the total price and fees. This involves
+/// no side effects so it should run before we spend anything on the reservation.
+pub async fn validate_tickets(store: Arc<Store>, event_id: &str, tickets: &[CheckoutTicket]) -> Result<(i64, i64)> {
+  let mut pre_purchase_checks: Vec<PrePurchaseCheck> = Vec::with_capacity(tickets.len());
+
+  for ticket in tickets {
+    match ticket {
+      CheckoutTicket::Primary {sale_account, ticket_nft, seat_index, ..} => {
+        pre_purchase_checks.push(Box::pin(pre_primary_purchase_checks(PrePurchaseChecksParams::Primary {
+          store: Arc::clone(&store),
+          event_id: event_id.to_string(),
+          seat_index: *seat_index,
+          sale_account: sale_account.clone(),
+          ticket_nft: ticket_nft.clone(),
+        })));
+      },
+      CheckoutTicket::Secondary {ticket_nft, ..} => {
+        pre_purchase_checks.push(Box::pin(pre_secondary_purchase_checks(PrePurchaseChecksParams::Secondary {
+          store: Arc::clone(&store),
+          ticket_nft: ticket_nft.clone(),
+          sell_listing_account: sell_listing_account(&store, event_id, ticket_nft)?.to_string(),
+        })));
+      },
+    }
+  }
+
+  // All the checks run concurrently so a cart costs the same time budget as a single ticket
+  let (price, fee) = timeout(
+    Duration::seconds(5).num_milliseconds() as u64,
+    try_join_all(pre_purchase_checks),
+  ).await??
+  .into_iter()
+  .fold((0, 0), |(total_price, total_fee), (price, fee)| (total_price + price, total_fee + fee));
+
+  Ok((price, fee))
+}
+
 pub async fn pre_primary_purchase_checks(params: PrePurchaseChecksParams) -> Result<(i64, i64)> {
   let (store, event_id, seat_index, sale_account, ticket_nft) = params.primary();
   let ticket_nft_state = &store.config.ticket_nft_state;
@@ -118,10 +239,7 @@ pub async fn pre_primary_purchase_checks(params: PrePurchaseChecksParams) -> Res
   // We need to check whether this ticket nft account exists. If it does it means that someone else
   // has already purchased it. We could alternatively load the event_capacity account and check the
   // bit array for availability.
-  let is_ticket_unavailable = store.rpc_client.account_exists(
-    &Pubkey::from_str(&ticket_nft)?,
-    CommitmentConfig::processed()
-  ).await?;
+  let is_ticket_unavailable = account_exists(&store, &Pubkey::from_str(&ticket_nft)?, true).await?;
 
   if is_ticket_unavailable {
     return Err(Report::msg("Ticket unavailable"))?
@@ -153,18 +271,33 @@ pub async fn pre_secondary_purchase_checks(params: PrePurchaseChecksParams) -> R
 
   // We need to check if the sell listing account exists. If it doesn't then it means that someone has already
   // filled that sell listing. The program closes sell listing accounts upon successefull completion.
-  let sell_listing_exists = store.rpc_client.account_exists(
-    &Pubkey::from_str(&sell_listing_account)?,
-    CommitmentConfig::processed()
-  ).await?;
+  let sell_listing_pubkey = Pubkey::from_str(&sell_listing_account)?;
+  let sell_listing_exists = account_exists(&store, &sell_listing_pubkey, false).await?;
 
-  if sell_listing_exists {
+  if !sell_listing_exists {
     return Err(Report::msg("Sell listing unavailable"))?
   }
 
+  // The DB is only an index of the on-chain state. The buyer is charged what the program will actually
+  // transfer to the seller, so the on-chain sell listing is the source of truth.
+  let on_chain_sell_listing = store.rpc_client.get_anchor_account_data::<SellListing>(&sell_listing_pubkey).await?;
+  let ticket_metadata = pda::ticket_metadata(&store.config.ticket_nft_state, &Pubkey::from_str(&ticket_nft)?).0;
+
+  if on_chain_sell_listing.seller.to_string() != sell_listing.seller {
+    return Err(CheckoutError::SellListingMismatch("seller".to_string()))?
+  }
+
+  if on_chain_sell_listing.ticket_metadata != ticket_metadata {
+    return Err(CheckoutError::SellListingMismatch("ticket_metadata".to_string()))?
+  }
+
+  if on_chain_sell_listing.ask_price as i64 != sell_listing.ask_price as i64 {
+    return Err(CheckoutError::SellListingMismatch("ask_price".to_string()))?
+  }
+
   calculate_price_and_fees(
     Arc::clone(&store),
-    sell_listing.ask_price as i64,
+    on_chain_sell_listing.ask_price as i64,
     store.config.secondary_market_protocol_fee,
     FILL_SELL_LISTING_COST_IN_SOL,
   ).await
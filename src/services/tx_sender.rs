@@ -0,0 +1,346 @@
+use std::{
+  time::Duration,
+  sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+use eyre::{Result, Report};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::info;
+use solana_client::{
+  nonblocking::rpc_client::RpcClient,
+  rpc_request::RpcRequest,
+};
+use solana_sdk::{
+  pubkey::Pubkey,
+  instruction::Instruction,
+  compute_budget::ComputeBudgetInstruction,
+  commitment_config::CommitmentConfig,
+  message::Message,
+  signature::Signature,
+  hash::Hash,
+  transaction::Transaction,
+};
+use crate::utils::error::CheckoutError;
+use super::{
+  program_errors::decode_program_error,
+  signer::{OperatorSigner, check_signing_policy},
+};
+
+/// Base fee paid for each signature of a transaction
+const LAMPORTS_PER_SIGNATURE: u64 = 5000;
+const MICRO_LAMPORTS_PER_LAMPORT: u64 = 1_000_000;
+/// How many times we will re-sign a transaction with a fresh blockhash if the previous one expired before it landed
+const MAX_SEND_ATTEMPTS: usize = 3;
+const CONFIRMATION_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Clone)]
+pub enum PriorityFee {
+  /// Fixed compute unit price in micro-lamports
+  Static(u64),
+  /// Compute unit price derived from the fees recently paid for the writable accounts of the transaction.
+  /// `percentile` is applied to the recent fees and the result is capped to `max` micro-lamports.
+  Dynamic {
+    percentile: u8,
+    max: u64,
+  },
+}
+
+#[derive(Clone)]
+pub struct ComputeBudget {
+  pub compute_unit_limit: u32,
+  pub priority_fee: PriorityFee,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RecentPrioritizationFee {
+  prioritization_fee: u64,
+}
+
+pub struct SentTx {
+  pub signature: Signature,
+  /// Total fee paid in lamports i.e. base fee plus priority fee
+  pub fee: u64,
+}
+
+#[derive(Clone, Copy)]
+pub enum OperatorSelection {
+  RoundRobin,
+  /// Picks the operator with the fewest transactions in flight
+  LeastLoaded,
+}
+
+struct Operator {
+  signer: Box<dyn OperatorSigner>,
+  in_flight: AtomicUsize,
+  /// Last known balance in lamports. It is u64::MAX until the first check.
+  balance: AtomicU64,
+}
+
+/// Marks a transaction as in flight for an operator until dropped
+struct InFlight<'a> {
+  operator: &'a Operator,
+}
+
+impl<'a> InFlight<'a> {
+  fn new(operator: &'a Operator) -> Self {
+    operator.in_flight.fetch_add(1, Ordering::Relaxed);
+    Self {operator}
+  }
+}
+
+impl<'a> Drop for InFlight<'a> {
+  fn drop(&mut self) {
+    self.operator.in_flight.fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+/// Sends the transactions signed by one of the operators of the pool. Every transaction is prepended with the
+/// compute budget instructions and is only considered sent once it has reached the confirmation commitment.
+pub struct TxSender {
+  rpc_client: RpcClient,
+  operators: Vec<Operator>,
+  selection: OperatorSelection,
+  next_operator: AtomicUsize,
+  /// Operators with a balance below this value in lamports are not used
+  min_balance: u64,
+  compute_budget: ComputeBudget,
+  /// State the txs are built and simulated against
+  reservation_commitment: CommitmentConfig,
+  confirmation_commitment: CommitmentConfig,
+}
+
+impl TxSender {
+  pub fn new(
+    rpc_endpoint: String,
+    signers: Vec<Box<dyn OperatorSigner>>,
+    selection: OperatorSelection,
+    min_balance: u64,
+    compute_budget: ComputeBudget,
+    reservation_commitment: CommitmentConfig,
+    confirmation_commitment: CommitmentConfig,
+  ) -> Self {
+    let operators = signers.into_iter()
+    .map(|signer| Operator {
+      signer,
+      in_flight: AtomicUsize::new(0),
+      balance: AtomicU64::new(u64::MAX),
+    })
+    .collect();
+
+    Self {
+      // simulations and preflight checks run against the reservation commitment
+      rpc_client: RpcClient::new_with_commitment(rpc_endpoint, reservation_commitment),
+      operators,
+      selection,
+      next_operator: AtomicUsize::new(0),
+      min_balance,
+      compute_budget,
+      reservation_commitment,
+      confirmation_commitment,
+    }
+  }
+
+  pub fn operators(&self) -> Vec<Pubkey> {
+    self.operators.iter().map(|operator| operator.signer.pubkey()).collect()
+  }
+
+  /// Returns the last known balance of each operator
+  pub fn balances(&self) -> Vec<(Pubkey, u64)> {
+    self.operators.iter()
+    .map(|operator| (operator.signer.pubkey(), operator.balance.load(Ordering::Relaxed)))
+    .collect()
+  }
+
+  pub fn has_funded_operator(&self) -> bool {
+    self.operators.iter().any(|operator| operator.balance.load(Ordering::Relaxed) >= self.min_balance)
+  }
+
+  pub async fn refresh_balances(&self) -> Result<Vec<(Pubkey, u64)>> {
+    for operator in &self.operators {
+      let balance = self.rpc_client.get_balance(&operator.signer.pubkey()).await?;
+      operator.balance.store(balance, Ordering::Relaxed);
+    }
+
+    Ok(self.balances())
+  }
+
+  fn select_operator(&self) -> Result<&Operator> {
+    let funded_operators = self.operators.iter()
+    .filter(|operator| operator.balance.load(Ordering::Relaxed) >= self.min_balance)
+    .collect::<Vec<_>>();
+
+    if funded_operators.is_empty() {
+      return Err(CheckoutError::TemporarilyUnavailable)?
+    }
+
+    let operator = match self.selection {
+      OperatorSelection::RoundRobin => {
+        let next = self.next_operator.fetch_add(1, Ordering::Relaxed);
+        funded_operators[next % funded_operators.len()]
+      },
+      OperatorSelection::LeastLoaded => funded_operators.into_iter()
+      .min_by_key(|operator| operator.in_flight.load(Ordering::Relaxed))
+      .expect("at least one operator"),
+    };
+
+    Ok(operator)
+  }
+
+  async fn compute_unit_price(&self, ix: &Instruction) -> Result<u64> {
+    match self.compute_budget.priority_fee {
+      PriorityFee::Static(price) => Ok(price),
+      PriorityFee::Dynamic {percentile, max} => {
+        let writable_accounts = ix.accounts.iter()
+        .filter(|account| account.is_writable)
+        .map(|account| account.pubkey.to_string())
+        .collect::<Vec<_>>();
+
+        let mut fees = self.rpc_client.send::<Vec<RecentPrioritizationFee>>(
+          RpcRequest::Custom {method: "getRecentPrioritizationFees"},
+          json!([writable_accounts]),
+        ).await?
+        .into_iter()
+        .map(|fee| fee.prioritization_fee)
+        .collect::<Vec<_>>();
+
+        if fees.is_empty() {
+          return Ok(0)
+        }
+
+        fees.sort_unstable();
+        let index = (fees.len() - 1) * percentile.min(100) as usize / 100;
+
+        Ok(fees[index].min(max))
+      },
+    }
+  }
+
+  /// Builds a transaction paid by the operator and signs it once the signing policy has been checked
+  async fn sign_tx(&self, signer: &dyn OperatorSigner, ixs: &[Instruction], blockhash: Hash) -> Result<Transaction> {
+    let mut message = Message::new(ixs, Some(&signer.pubkey()));
+    message.recent_blockhash = blockhash;
+    check_signing_policy(&message)?;
+
+    let mut tx = Transaction::new_unsigned(message);
+    tx.signatures = vec![signer.sign_message(&tx.message_data()).await?];
+
+    Ok(tx)
+  }
+
+  /// Runs the transaction against the current state so that program errors are caught before we pay for the tx.
+  /// Errors coming from the Ticketland programs are decoded into a `CheckoutError`.
+  async fn simulate_tx(&self, ixs: &[Instruction], tx: &Transaction) -> Result<()> {
+    let result = self.rpc_client.simulate_transaction(tx).await?.value;
+
+    if let Some(error) = result.err {
+      let logs = result.logs.unwrap_or_default();
+
+      return match decode_program_error(ixs, &error, &logs) {
+        Some(checkout_error) => Err(checkout_error)?,
+        None => Err(Report::msg(format!("Simulation failed: {} {:?}", error, logs))),
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Waits until the transaction reaches the confirmation commitment. Returns false if the blockhash
+  /// expired before that happened in which case the transaction will never land.
+  async fn confirm_tx(&self, ixs: &[Instruction], signature: &Signature, last_valid_block_height: u64) -> Result<bool> {
+    loop {
+      tokio::time::sleep(CONFIRMATION_POLL_INTERVAL).await;
+
+      let status = self.rpc_client.get_signature_statuses(&[*signature]).await?
+      .value
+      .into_iter()
+      .next()
+      .flatten();
+
+      if let Some(status) = status {
+        if let Some(error) = status.err {
+          return Err(decode_program_error(ixs, &error, &[]).unwrap_or(CheckoutError::TxFailed(error.to_string())))?
+        }
+
+        if status.satisfies_commitment(self.confirmation_commitment) {
+          return Ok(true)
+        }
+
+        // Already processed; it can't be dropped because of an expired blockhash anymore
+        continue
+      }
+
+      if self.rpc_client.get_block_height().await? > last_valid_block_height {
+        return Ok(false)
+      }
+    }
+  }
+
+  /// Sends the instruction returned by `build_ix`, which is given the operator that will sign and pay for the tx
+  pub async fn send_tx<F>(&self, build_ix: F) -> Result<SentTx>
+  where
+    F: FnOnce(Pubkey) -> Result<Instruction>,
+  {
+    self.send_tx_with(self.select_operator()?, build_ix).await
+  }
+
+  /// Same as `send_tx` but the tx is signed by the given operator e.g. because it has to receive the rent of
+  /// an account it funded
+  pub async fn send_tx_as<F>(&self, operator: &Pubkey, build_ix: F) -> Result<SentTx>
+  where
+    F: FnOnce(Pubkey) -> Result<Instruction>,
+  {
+    let operator = self.operators.iter()
+    .find(|candidate| candidate.signer.pubkey() == *operator)
+    .ok_or(Report::msg(format!("Unknown operator {}", operator)))?;
+
+    self.send_tx_with(operator, build_ix).await
+  }
+
+  async fn send_tx_with<F>(&self, operator: &Operator, build_ix: F) -> Result<SentTx>
+  where
+    F: FnOnce(Pubkey) -> Result<Instruction>,
+  {
+    let _in_flight = InFlight::new(operator);
+    let signer = operator.signer.as_ref();
+    let ix = build_ix(signer.pubkey())?;
+
+    let compute_unit_limit = self.compute_budget.compute_unit_limit;
+    let compute_unit_price = self.compute_unit_price(&ix).await?;
+
+    let ixs = vec![
+      ComputeBudgetInstruction::set_compute_unit_limit(compute_unit_limit),
+      ComputeBudgetInstruction::set_compute_unit_price(compute_unit_price),
+      ix,
+    ];
+
+    for attempt in 1..=MAX_SEND_ATTEMPTS {
+      let (blockhash, last_valid_block_height) = self.rpc_client
+      .get_latest_blockhash_with_commitment(self.reservation_commitment)
+      .await?;
+      let tx = self.sign_tx(signer, &ixs, blockhash).await?;
+      self.simulate_tx(&ixs, &tx).await?;
+
+      let signature = self.rpc_client.send_transaction(&tx).await?;
+
+      if !self.confirm_tx(&ixs, &signature, last_valid_block_height).await? {
+        info!("Blockhash expired before tx {} was confirmed. Attempt {}/{}", signature, attempt, MAX_SEND_ATTEMPTS);
+        continue
+      }
+
+      let priority_fee = (compute_unit_limit as u64 * compute_unit_price + MICRO_LAMPORTS_PER_LAMPORT - 1) / MICRO_LAMPORTS_PER_LAMPORT;
+      let fee = LAMPORTS_PER_SIGNATURE * tx.signatures.len() as u64 + priority_fee;
+      info!(
+        "Confirmed tx {} signed by {} with compute unit price {} paying {} lamports in fees",
+        signature,
+        signer.pubkey(),
+        compute_unit_price,
+        fee,
+      );
+
+      return Ok(SentTx {signature, fee})
+    }
+
+    Err(CheckoutError::TxNotConfirmed)?
+  }
+}
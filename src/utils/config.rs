@@ -1,23 +1,119 @@
-use std::env;
-use solana_sdk::pubkey::Pubkey;
+use std::{
+  env,
+  str::FromStr,
+  collections::HashMap,
+};
+use solana_sdk::{
+  pubkey::Pubkey,
+  commitment_config::CommitmentConfig,
+};
 use solana_web3_rust::utils::pubkey_from_str;
+use crate::{
+  models::{
+    payment_intent::PaymentIntent,
+    envelope::Versioned,
+  },
+  services::tx_sender::{ComputeBudget, PriorityFee, OperatorSelection},
+};
+
+/// Commitment levels used by the different operations
+#[derive(Clone, Copy)]
+pub struct Commitments {
+  /// Availability checks i.e. whether a ticket nft or a sell listing account exists
+  pub read: CommitmentConfig,
+  /// State the reservation txs are built and simulated against
+  pub reservation: CommitmentConfig,
+  /// A reservation tx is considered sent once it reaches this commitment
+  pub confirmation: CommitmentConfig,
+  /// If set, an availability check that finds the ticket unavailable is repeated at this commitment
+  /// since the state at the read commitment can be rolled back
+  pub unavailable_double_check: Option<CommitmentConfig>,
+}
+
+/// The remote signing service that holds the operator keys
+#[derive(Clone)]
+pub struct RemoteSignerConfig {
+  pub url: String,
+  /// Sent as a bearer token with every signing request
+  pub token: String,
+}
+
+/// Maximum number of tickets a buyer can own and hold. Limits can be set for an event or for a ticket type of an event.
+#[derive(Default)]
+pub struct PurchaseLimits {
+  pub default: Option<u32>,
+  pub events: HashMap<String, u32>,
+  pub ticket_types: HashMap<(String, u8), u32>,
+}
+
+impl PurchaseLimits {
+  pub fn event_limit(&self, event_id: &str) -> Option<u32> {
+    self.events.get(event_id).copied().or(self.default)
+  }
+
+  pub fn ticket_type_limit(&self, event_id: &str, ticket_type_index: u8) -> Option<u32> {
+    self.ticket_types.get(&(event_id.to_string(), ticket_type_index)).copied()
+  }
+}
 
 pub struct Config {
   pub postgres_uri: String,
   pub rabbitmq_uri: String,
   pub retry_ttl: u16,
+  /// How many checkouts of each create_payment queue are processed at the same time. Used as the prefetch count.
+  /// Primary and secondary checkouts have their own queue so that resale traffic can't delay an on-sale.
+  pub primary_concurrency: u16,
+  pub secondary_concurrency: u16,
+  pub legacy_concurrency: u16,
+  /// Number of partitions the events are hashed to. Each partition processes at most `event_partition_concurrency`
+  /// requests at a time across all the instances. 0 disables partitioning.
+  pub event_partitions: usize,
+  pub event_partition_concurrency: usize,
+  /// Version of the PaymentIntent messages we publish. 0 publishes the legacy format without an envelope. Defaults
+  /// to the layout without the custodial wallet; set it to the current version once the consumers can decode it.
+  pub payment_intent_version: u16,
+  /// CreatePayment messages older than this many seconds are not processed
+  pub max_message_age: u64,
+  /// Recipient signatures older than this many seconds are rejected
+  pub recipient_signature_max_age: u64,
+  /// CreatePayment requests a buyer can send per minute
+  pub max_requests_per_minute: u32,
+  /// Tickets a buyer can hold at the same time for a single event
+  pub max_active_holds: usize,
+  pub purchase_limits: PurchaseLimits,
   pub redis_host: String,
   pub redis_port: u16,
   pub redis_password: String,
   pub rpc_endpoint: String,
   pub ticketland_dapp: String,
   pub stripe_key: String,
+  /// Overrides the Stripe API base url i.e. to point to a fake payment provider in tests
+  pub stripe_api_url: Option<String>,
   pub ticket_sale_state: Pubkey,
   pub ticket_nft_state: Pubkey,
   pub secondary_market_state: Pubkey,
   pub ticket_purchase_protocol_fee: i64,
   pub secondary_market_protocol_fee: i64,
-  pub operator_priv_key: String,
+  pub operator_priv_keys: Vec<String>,
+  /// When set the operator keys live in the remote signing service and only their pubkeys are configured here
+  pub remote_signer: Option<RemoteSignerConfig>,
+  pub operator_pubkeys: Vec<String>,
+  /// Key management service that holds the custodial wallets of buyers without a wallet
+  pub custodial_wallet_url: Option<String>,
+  pub operator_selection: OperatorSelection,
+  pub compute_budget: ComputeBudget,
+  pub commitments: Commitments,
+  pub rent_sweeper_interval: u64,
+  pub balance_check_interval: u64,
+  /// AES-256 key the client secrets are encrypted with in the outbox
+  pub outbox_encryption_key: Vec<u8>,
+  pub outbox_relay_interval: u64,
+  /// Operator balance in lamports below which we log warnings
+  pub operator_balance_warning: u64,
+  /// Operator balance in lamports below which new checkouts are rejected
+  pub operator_balance_critical: u64,
+  /// Port of the Prometheus metrics endpoint
+  pub metrics_port: u16,
 }
 
 impl Config {
@@ -32,14 +128,141 @@ impl Config {
         rpc_endpoint: env::var("RPC_ENDPOINT").unwrap(),
         ticketland_dapp: env::var("TICKETLAND_DAPP").unwrap(),
         stripe_key: env::var("STRIPE_CLIENT_SECRET").unwrap(),
+        stripe_api_url: env::var("STRIPE_API_URL").ok(),
         ticket_sale_state: pubkey_from_str(&env::var("TICKET_SALE_STATE").unwrap()).unwrap(),
         secondary_market_state: pubkey_from_str(&env::var("SECONDARY_PROGRAM_STATE").unwrap()).unwrap(),
         ticket_purchase_protocol_fee: env::var("TICKET_PURCHASE_PROTOCOL_FEE").unwrap().parse::<i64>().unwrap(),
         ticket_nft_state: pubkey_from_str(&env::var("TICKET_NFT_STATE").unwrap()).unwrap(),
         secondary_market_protocol_fee: env::var("SECONDARY_MARKET_PROTOCOL_FEE").unwrap().parse::<i64>().unwrap(),
-        operator_priv_key: env::var("OPERATOR_PRIV_KEY").unwrap(),
+        operator_priv_keys: Self::list("OPERATOR_PRIV_KEYS", "OPERATOR_PRIV_KEY"),
+        remote_signer: Self::remote_signer(),
+        operator_pubkeys: Self::list("OPERATOR_PUBKEYS", "OPERATOR_PUBKEY"),
+        custodial_wallet_url: env::var("CUSTODIAL_WALLET_URL").ok(),
+        operator_selection: match env::var("OPERATOR_SELECTION").unwrap_or("round_robin".to_string()).as_str() {
+          "least_loaded" => OperatorSelection::LeastLoaded,
+          _ => OperatorSelection::RoundRobin,
+        },
         retry_ttl: env::var("RETRY_TTL").unwrap().parse::<u16>().unwrap(),
+        primary_concurrency: Self::concurrency("PRIMARY_CONCURRENCY", "20"),
+        secondary_concurrency: Self::concurrency("SECONDARY_CONCURRENCY", "5"),
+        legacy_concurrency: Self::concurrency("LEGACY_CONCURRENCY", "5"),
+        event_partitions: env::var("EVENT_PARTITIONS").unwrap_or("0".to_string()).parse::<usize>().unwrap(),
+        event_partition_concurrency: Self::event_partition_concurrency(),
+        payment_intent_version: env::var("PAYMENT_INTENT_VERSION").unwrap_or(PaymentIntent::LEGACY_LAYOUT.to_string()).parse::<u16>().unwrap(),
+        max_message_age: env::var("MAX_MESSAGE_AGE").unwrap_or("120".to_string()).parse::<u64>().unwrap(),
+        recipient_signature_max_age: env::var("RECIPIENT_SIGNATURE_MAX_AGE").unwrap_or("600".to_string()).parse::<u64>().unwrap(),
+        max_requests_per_minute: env::var("MAX_REQUESTS_PER_MINUTE").unwrap_or("10".to_string()).parse::<u32>().unwrap(),
+        max_active_holds: env::var("MAX_ACTIVE_HOLDS").unwrap_or("10".to_string()).parse::<usize>().unwrap(),
+        purchase_limits: Self::purchase_limits(),
+        compute_budget: Self::compute_budget(),
+        commitments: Self::commitments(),
+        rent_sweeper_interval: env::var("RENT_SWEEPER_INTERVAL").unwrap_or("3600".to_string()).parse::<u64>().unwrap(),
+        balance_check_interval: env::var("BALANCE_CHECK_INTERVAL").unwrap_or("30".to_string()).parse::<u64>().unwrap(),
+        outbox_encryption_key: Self::outbox_encryption_key(),
+        outbox_relay_interval: env::var("OUTBOX_RELAY_INTERVAL").unwrap_or("5".to_string()).parse::<u64>().unwrap(),
+        operator_balance_warning: env::var("OPERATOR_BALANCE_WARNING").unwrap_or("1000000000".to_string()).parse::<u64>().unwrap(),
+        operator_balance_critical: env::var("OPERATOR_BALANCE_CRITICAL").unwrap_or("100000000".to_string()).parse::<u64>().unwrap(),
+        metrics_port: env::var("METRICS_PORT").unwrap_or("8000".to_string()).parse::<u16>().unwrap(),
       }
     )
   }
+
+  /// Reads a comma separated list of values i.e. OPERATOR_PRIV_KEYS. If missing the single value var i.e. OPERATOR_PRIV_KEY is used.
+  fn list(var: &str, single_var: &str) -> Vec<String> {
+    env::var(var)
+    .or(env::var(single_var))
+    .unwrap_or_default()
+    .split(',')
+    .map(|priv_key| priv_key.trim().to_string())
+    .filter(|priv_key| !priv_key.is_empty())
+    .collect()
+  }
+
+  /// PURCHASE_LIMITS is a list of `event_id=limit` and `event_id:ticket_type_index=limit` entries.
+  /// DEFAULT_PURCHASE_LIMIT applies to the events that aren't listed.
+  fn purchase_limits() -> PurchaseLimits {
+    let mut purchase_limits = PurchaseLimits {
+      default: env::var("DEFAULT_PURCHASE_LIMIT").ok().map(|limit| limit.parse::<u32>().unwrap()),
+      ..Default::default()
+    };
+
+    for entry in Self::list("PURCHASE_LIMITS", "PURCHASE_LIMITS") {
+      let (target, limit) = entry.split_once('=').unwrap();
+      let limit = limit.parse::<u32>().unwrap();
+
+      match target.split_once(':') {
+        Some((event_id, ticket_type_index)) => {
+          purchase_limits.ticket_types.insert((event_id.to_string(), ticket_type_index.parse::<u8>().unwrap()), limit);
+        },
+        None => {
+          purchase_limits.events.insert(target.to_string(), limit);
+        },
+      }
+    }
+
+    purchase_limits
+  }
+
+  /// A prefetch count of 0 would mean no limit at all
+  fn concurrency(var: &str, default: &str) -> u16 {
+    let concurrency = env::var(var).unwrap_or(default.to_string()).parse::<u16>().unwrap();
+    assert!(concurrency > 0, "{} must be at least 1", var);
+
+    concurrency
+  }
+
+  fn event_partition_concurrency() -> usize {
+    let concurrency = env::var("EVENT_PARTITION_CONCURRENCY").unwrap_or("1".to_string()).parse::<usize>().unwrap();
+    assert!(concurrency > 0, "EVENT_PARTITION_CONCURRENCY must be at least 1");
+
+    concurrency
+  }
+
+  fn remote_signer() -> Option<RemoteSignerConfig> {
+    let url = env::var("REMOTE_SIGNER_URL").ok()?;
+
+    Some(RemoteSignerConfig {
+      url,
+      token: env::var("REMOTE_SIGNER_TOKEN").expect("REMOTE_SIGNER_TOKEN is required when REMOTE_SIGNER_URL is set"),
+    })
+  }
+
+  fn outbox_encryption_key() -> Vec<u8> {
+    let key = env::var("OUTBOX_ENCRYPTION_KEY")
+    .expect("OUTBOX_ENCRYPTION_KEY is required. Generate one with `openssl rand -base64 32`");
+    let key = base64::decode(key).expect("OUTBOX_ENCRYPTION_KEY must be base64 encoded");
+    assert!(key.len() == 32, "OUTBOX_ENCRYPTION_KEY must be 32 bytes long");
+
+    key
+  }
+
+  fn commitment(var: &str, default: &str) -> CommitmentConfig {
+    CommitmentConfig::from_str(&env::var(var).unwrap_or(default.to_string())).unwrap()
+  }
+
+  fn commitments() -> Commitments {
+    Commitments {
+      read: Self::commitment("READ_COMMITMENT", "processed"),
+      reservation: Self::commitment("RESERVATION_COMMITMENT", "confirmed"),
+      confirmation: Self::commitment("CONFIRMATION_COMMITMENT", "confirmed"),
+      unavailable_double_check: env::var("UNAVAILABLE_DOUBLE_CHECK_COMMITMENT").ok()
+      .map(|commitment| CommitmentConfig::from_str(&commitment).unwrap()),
+    }
+  }
+
+  /// PRIORITY_FEE can either be `dynamic` or a fixed compute unit price in micro-lamports
+  fn compute_budget() -> ComputeBudget {
+    let priority_fee = match env::var("PRIORITY_FEE").unwrap_or("0".to_string()).as_str() {
+      "dynamic" => PriorityFee::Dynamic {
+        percentile: env::var("PRIORITY_FEE_PERCENTILE").unwrap_or("75".to_string()).parse::<u8>().unwrap(),
+        max: env::var("MAX_PRIORITY_FEE").unwrap_or("1000000".to_string()).parse::<u64>().unwrap(),
+      },
+      price => PriorityFee::Static(price.parse::<u64>().unwrap()),
+    };
+
+    ComputeBudget {
+      compute_unit_limit: env::var("COMPUTE_UNIT_LIMIT").unwrap_or("200000".to_string()).parse::<u32>().unwrap(),
+      priority_fee,
+    }
+  }
 }
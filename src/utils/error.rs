@@ -0,0 +1,37 @@
+use thiserror::Error;
+
+/// Errors that are returned to the buyer in the `PaymentIntent` message rather than retried
+#[derive(Error, Debug)]
+pub enum CheckoutError {
+  #[error("Checkout temporarily unavailable")]
+  TemporarilyUnavailable,
+  #[error("Request expired")]
+  RequestExpired,
+  #[error("Too many requests")]
+  RateLimited,
+  #[error("Too many tickets held at the same time")]
+  TooManyHolds,
+  #[error("Purchase limit of {0} tickets reached")]
+  PurchaseLimitReached(u32),
+  #[error("Invalid recipient: {0}")]
+  InvalidRecipient(String),
+  #[error("Ticket unavailable")]
+  TicketUnavailable,
+  #[error("Ticket reserved by another buyer")]
+  TicketReserved,
+  #[error("Sell listing unavailable")]
+  SellListingUnavailable,
+  #[error("Sell listing does not match the on-chain state: {0}")]
+  SellListingMismatch(String),
+  #[error("Sale is not active")]
+  SaleNotActive,
+  #[error("Reservation rejected by {program}: {error}")]
+  ProgramError {
+    program: String,
+    error: String,
+  },
+  #[error("Reservation failed: {0}")]
+  TxFailed(String),
+  #[error("Reservation could not be confirmed")]
+  TxNotConfirmed,
+}
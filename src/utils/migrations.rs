@@ -0,0 +1,21 @@
+use diesel_async::{
+  AsyncPgConnection, SimpleAsyncConnection,
+  pooled_connection::deadpool::Pool,
+};
+use eyre::Result;
+
+/// The tables owned by this service. The shared ones are migrated by ticketland-data.
+/// Every migration must be safe to run again since they all run on each start.
+const MIGRATIONS: [&str; 1] = [
+  include_str!("../../migrations/2026-10-18-000000_create_payment_outbox/up.sql"),
+];
+
+pub async fn run(sql_pool: &Pool<AsyncPgConnection>) -> Result<()> {
+  let mut connection = sql_pool.get().await?;
+
+  for migration in MIGRATIONS {
+    connection.batch_execute(migration).await?;
+  }
+
+  Ok(())
+}
@@ -1,2 +1,4 @@
 pub mod store;
 pub mod config;
+pub mod error;
+pub mod migrations;
@@ -1,4 +1,8 @@
 use std::sync::Arc;
+use diesel_async::{
+  AsyncPgConnection,
+  pooled_connection::{AsyncDieselConnectionManager, deadpool::Pool},
+};
 use ticketland_data::connection_pool::ConnectionPool;
 use ticketland_core::{
   services::{
@@ -6,38 +10,90 @@ use ticketland_core::{
   },
 };
 use solana_web3_rust::rpc_client::RpcClient;
-use super::config::Config;
-use crate::queue::payment_producer::PaymentProducer;
+use solana_client::nonblocking::rpc_client::RpcClient as SolanaRpcClient;
+use super::{config::Config, migrations};
+use crate::{
+  queue::{
+    payment_producer::PaymentProducer,
+    status_producer::StatusProducer,
+    dead_letter_producer::DeadLetterProducer,
+  },
+  services::{
+    tx_sender::TxSender,
+    signer::operator_signers,
+  },
+};
 
 pub struct Store {
   pub config: Config,
   pub pg_pool: ConnectionPool,
+  /// Used for the tables this service owns i.e. the payment outbox and for the queries ticketland-data doesn't have
+  pub sql_pool: Pool<AsyncPgConnection>,
   pub redis_pool: redis::ConnectionPool,
   pub redlock: Arc<RedLock>,
   pub rpc_client: Arc<RpcClient>,
+  /// Used for the reads the rpc client above doesn't expose i.e. `getMultipleAccounts`
+  pub batch_rpc_client: Arc<SolanaRpcClient>,
+  pub tx_sender: TxSender,
   pub payment_producer: PaymentProducer,
+  pub status_producer: StatusProducer,
+  pub dead_letter_producer: DeadLetterProducer,
 }
 
 impl Store {
   pub async fn new() -> Self {
     let config = Config::new().unwrap();
     let pg_pool = ConnectionPool::new(&config.postgres_uri).await;
+    let sql_pool = Pool::builder(AsyncDieselConnectionManager::<AsyncPgConnection>::new(&config.postgres_uri))
+    .build()
+    .unwrap();
+    migrations::run(&sql_pool).await.unwrap();
     let redis_pool = redis::ConnectionPool::new(&config.redis_host, &config.redis_password, config.redis_port);
     let redlock = Arc::new(RedLock::new(vec![&config.redis_host], &config.redis_password));
-    let rpc_client = Arc::new(RpcClient::new(config.rpc_endpoint.clone(), Some(config.operator_priv_key.clone())));
+    // The rpc client is only used for reads. All operator transactions go through the tx sender.
+    let rpc_client = Arc::new(RpcClient::new(config.rpc_endpoint.clone(), None));
+    let batch_rpc_client = Arc::new(SolanaRpcClient::new_with_commitment(
+      config.rpc_endpoint.clone(),
+      config.commitments.read,
+    ));
+    let tx_sender = TxSender::new(
+      config.rpc_endpoint.clone(),
+      operator_signers(config.remote_signer.as_ref(), &config.operator_priv_keys, &config.operator_pubkeys).unwrap(),
+      config.operator_selection,
+      config.operator_balance_critical,
+      config.compute_budget.clone(),
+      config.commitments.reservation,
+      config.commitments.confirmation,
+    );
 
     let payment_producer = PaymentProducer::new(
       config.rabbitmq_uri.clone(),
       config.retry_ttl,
+      config.payment_intent_version,
+    ).await;
+
+    let status_producer = StatusProducer::new(
+      config.rabbitmq_uri.clone(),
+      config.retry_ttl,
+    ).await;
+
+    let dead_letter_producer = DeadLetterProducer::new(
+      config.rabbitmq_uri.clone(),
+      config.retry_ttl,
     ).await;
 
     Self {
       config,
       pg_pool,
+      sql_pool,
       redis_pool,
       redlock,
       rpc_client,
+      batch_rpc_client,
+      tx_sender,
       payment_producer,
+      status_producer,
+      dead_letter_producer,
     }
   }
 }
@@ -0,0 +1,67 @@
+//! Minimal stand-in for the Stripe API. It only implements the endpoints used by `create_payment`.
+
+use std::net::TcpListener;
+use actix_web::{web, App, HttpServer, HttpResponse};
+use chrono::Utc;
+use eyre::Result;
+use serde_json::json;
+
+pub const CLIENT_SECRET: &str = "pi_test_secret_test";
+
+async fn create_customer() -> HttpResponse {
+  HttpResponse::Ok().json(json!({
+    "id": "cus_test",
+    "object": "customer",
+    "created": Utc::now().timestamp(),
+    "livemode": false,
+    "metadata": {},
+  }))
+}
+
+async fn create_payment_intent(params: web::Form<Vec<(String, String)>>) -> HttpResponse {
+  let amount = params.iter()
+  .find(|(key, _)| key == "amount")
+  .and_then(|(_, value)| value.parse::<i64>().ok())
+  .unwrap_or_default();
+
+  HttpResponse::Ok().json(json!({
+    "id": "pi_test",
+    "object": "payment_intent",
+    "amount": amount,
+    "amount_capturable": 0,
+    "amount_received": 0,
+    "capture_method": "automatic",
+    "confirmation_method": "automatic",
+    "created": Utc::now().timestamp(),
+    "currency": "usd",
+    "livemode": false,
+    "metadata": {},
+    "payment_method_types": ["card"],
+    "status": "requires_payment_method",
+    "client_secret": CLIENT_SECRET,
+    "charges": {
+      "object": "list",
+      "data": [],
+      "has_more": false,
+      "url": "/v1/charges",
+    },
+  }))
+}
+
+/// Starts the server on a random port and returns its base url
+pub async fn start() -> Result<String> {
+  let listener = TcpListener::bind("127.0.0.1:0")?;
+  let url = format!("http://{}/v1/", listener.local_addr()?);
+
+  let server = HttpServer::new(|| {
+    App::new()
+    .route("/v1/customers", web::post().to(create_customer))
+    .route("/v1/payment_intents", web::post().to(create_payment_intent))
+  })
+  .listen(listener)?
+  .run();
+
+  actix::spawn(server);
+
+  Ok(url)
+}
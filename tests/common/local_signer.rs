@@ -0,0 +1,68 @@
+//! Stand-in for the remote signing service. It keeps the keys in memory, checks the bearer token and applies the
+//! same signing policy.
+
+use std::{
+  net::TcpListener,
+  sync::Arc,
+};
+use actix_web::{web, App, HttpServer, HttpRequest, HttpResponse, http::header::AUTHORIZATION};
+use eyre::Result;
+use solana_sdk::{
+  message::Message,
+  signature::{Keypair, Signer},
+};
+use fiat_checkout_manager::services::signer::{SignRequest, SignResponse, check_signing_policy};
+
+struct LocalSigningService {
+  keypairs: Vec<Keypair>,
+  token: String,
+}
+
+async fn sign(service: web::Data<Arc<LocalSigningService>>, http_req: HttpRequest, req: web::Json<SignRequest>) -> HttpResponse {
+  let authorization = http_req.headers().get(AUTHORIZATION).and_then(|value| value.to_str().ok());
+  if authorization != Some(format!("Bearer {}", service.token).as_str()) {
+    return HttpResponse::Unauthorized().finish()
+  }
+
+  let keypair = match service.keypairs.iter().find(|keypair| keypair.pubkey().to_string() == req.pubkey) {
+    Some(keypair) => keypair,
+    None => return HttpResponse::NotFound().body("Unknown pubkey"),
+  };
+
+  let message_data = match base64::decode(&req.message) {
+    Ok(message_data) => message_data,
+    Err(_) => return HttpResponse::BadRequest().body("Invalid message encoding"),
+  };
+
+  let message = match bincode::deserialize::<Message>(&message_data) {
+    Ok(message) => message,
+    Err(_) => return HttpResponse::BadRequest().body("Invalid message"),
+  };
+
+  if let Err(error) = check_signing_policy(&message) {
+    return HttpResponse::Forbidden().body(error.to_string())
+  }
+
+  HttpResponse::Ok().json(SignResponse {
+    signature: keypair.sign_message(&message_data).to_string(),
+  })
+}
+
+/// Starts the server on a random port and returns its base url
+pub async fn start(keypairs: Vec<Keypair>, token: &str) -> Result<String> {
+  let listener = TcpListener::bind("127.0.0.1:0")?;
+  let url = format!("http://{}", listener.local_addr()?);
+  let service = Arc::new(LocalSigningService {keypairs, token: token.to_string()});
+
+  let server = HttpServer::new(move || {
+    App::new()
+    .app_data(web::Data::new(Arc::clone(&service)))
+    .route("/sign", web::post().to(sign))
+  })
+  .listen(listener)?
+  .run();
+
+  actix::spawn(server);
+
+  Ok(url)
+}
@@ -0,0 +1,359 @@
+//! Harness for the integration tests. Each test gets a fresh `solana-test-validator`. Redis, Postgres and RabbitMQ
+//! are started once with `tests/docker-compose.yml`; set `TEST_SERVICES_RUNNING` to use services you started yourself.
+//!
+//! The configuration is read from `.env.test` which contains the usual service variables plus:
+//!
+//! - `PROGRAM_ARTIFACTS_DIR` directory with the `ticket_sale.so`, `ticket_nft.so` and `secondary_market.so` builds
+//! - `TEST_FIXTURES_DIR` (default `tests/fixtures`) with the program state accounts under `accounts/` and the
+//!   `seed.sql` for Postgres. `dump_accounts.sh` writes the accounts listed in `.env.test` from a cluster where the
+//!   fixture event exists.
+//! - `TEST_EVENT_ID`, `TEST_SALE_ACCOUNT`, `TEST_TICKET_TYPE_INDEX` the fixture event and its fixed price sale
+//! - `TEST_BUYER_UID`, `TEST_RECIPIENT` a buyer and one of their linked wallets
+//! - `TEST_OTHER_RECIPIENT` a wallet of another buyer
+//! - `TEST_SELL_LISTING_TICKET_NFT` a ticket of the fixture event that is listed on the secondary market
+//!
+//! The ticketland-data migrations are applied before `seed.sql`. Setup fails straight away if the programs or the
+//! fixture accounts are missing. The validator listens on fixed ports so run the tests with `--test-threads=1`.
+
+// Every test binary includes the harness but only uses part of it
+#![allow(dead_code)]
+
+pub mod fake_stripe;
+
+use std::{
+  env,
+  fs,
+  path::{Path, PathBuf},
+  process::{Child, Command, Stdio},
+  sync::{Arc, Once},
+  time::Duration,
+};
+use eyre::{Result, Report};
+use serde_json::json;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::{
+  pubkey::Pubkey,
+  signature::{Keypair, Signer},
+  native_token::LAMPORTS_PER_SOL,
+};
+use program_artifacts::{
+  ticket_sale,
+  ticket_nft::{self, pda},
+  secondary_market,
+  event_registry::account_data::EventId,
+};
+use price_feed::actors::price::get_price_key;
+use serde_json::Value;
+use fiat_checkout_manager::{
+  utils::store::Store,
+  queue::create_payment_consumer::CreatePaymentHandler,
+  services::ticket_hold::release_tickets,
+};
+
+const RPC_ENDPOINT: &str = "http://127.0.0.1:8899";
+/// Seat of the fixture sale whose ticket nft is created before the validator starts
+pub const MINTED_SEAT_INDEX: u32 = 0;
+/// Seats of the fixture sale the tests may hold
+const HELD_SEATS: u32 = 10;
+const SOL_PRICE: &str = "20";
+
+static START_SERVICES: Once = Once::new();
+
+pub struct Fixture {
+  pub event_id: String,
+  pub sale_account: String,
+  pub ticket_type_index: u8,
+  pub buyer_uid: String,
+  pub recipient: String,
+  pub other_recipient: String,
+  pub sell_listing_ticket_nft: String,
+}
+
+impl Fixture {
+  fn new() -> Self {
+    Self {
+      event_id: env::var("TEST_EVENT_ID").unwrap(),
+      sale_account: env::var("TEST_SALE_ACCOUNT").unwrap(),
+      ticket_type_index: env::var("TEST_TICKET_TYPE_INDEX").unwrap().parse::<u8>().unwrap(),
+      buyer_uid: env::var("TEST_BUYER_UID").unwrap(),
+      recipient: env::var("TEST_RECIPIENT").unwrap(),
+      other_recipient: env::var("TEST_OTHER_RECIPIENT").unwrap(),
+      sell_listing_ticket_nft: env::var("TEST_SELL_LISTING_TICKET_NFT").unwrap(),
+    }
+  }
+
+  pub fn ticket_nft(&self, seat_index: u32) -> Pubkey {
+    pda::ticket_nft(
+      &pubkey(&env::var("TICKET_NFT_STATE").unwrap()),
+      seat_index,
+      &EventId(self.event_id.clone()).val(),
+      self.ticket_type_index,
+    ).0
+  }
+}
+
+/// Kills the validator when the test is over
+struct Validator(Child);
+
+impl Drop for Validator {
+  fn drop(&mut self) {
+    let _ = self.0.kill();
+    let _ = self.0.wait();
+  }
+}
+
+pub struct TestContext {
+  _validator: Validator,
+  pub fixture: Fixture,
+  pub store: Arc<Store>,
+  pub handler: CreatePaymentHandler,
+  pub rpc_client: RpcClient,
+}
+
+fn pubkey(value: &str) -> Pubkey {
+  value.parse::<Pubkey>().unwrap()
+}
+
+fn fixtures_dir() -> PathBuf {
+  PathBuf::from(env::var("TEST_FIXTURES_DIR").unwrap_or("tests/fixtures".to_string()))
+}
+
+/// Writes an account in the format `solana-test-validator --account` expects
+fn write_account(dir: &Path, address: &Pubkey, owner: &Pubkey, data: &[u8]) -> Result<PathBuf> {
+  let path = dir.join(format!("{}.json", address));
+  let account = json!({
+    "pubkey": address.to_string(),
+    "account": {
+      "lamports": LAMPORTS_PER_SOL,
+      "data": [base64::encode(data), "base64"],
+      "owner": owner.to_string(),
+      "executable": false,
+      "rentEpoch": 0,
+    },
+  });
+
+  fs::write(&path, account.to_string())?;
+
+  Ok(path)
+}
+
+/// Program builds the validator loads, checked before it starts so that a missing one fails the test straight away
+fn programs() -> Result<Vec<(Pubkey, PathBuf)>> {
+  let artifacts_dir = env::var("PROGRAM_ARTIFACTS_DIR")
+  .map_err(|_| Report::msg("PROGRAM_ARTIFACTS_DIR must point to the program builds"))?;
+
+  [
+    (ticket_sale::program_id(), "ticket_sale"),
+    (ticket_nft::program_id(), "ticket_nft"),
+    (secondary_market::program_id(), "secondary_market"),
+  ]
+  .into_iter()
+  .map(|(program_id, name)| {
+    let path = Path::new(&artifacts_dir).join(format!("{}.so", name));
+
+    if !path.exists() {
+      return Err(Report::msg(format!("{} is missing", path.display())))
+    }
+
+    Ok((program_id, path))
+  })
+  .collect()
+}
+
+/// The program state accounts of the fixture event. They are loaded from `accounts/<pubkey>.json`.
+fn fixture_accounts(fixture: &Fixture) -> Result<Vec<(Pubkey, PathBuf)>> {
+  let ticket_nft_state = pubkey(&env::var("TICKET_NFT_STATE").unwrap());
+  let secondary_market_state = pubkey(&env::var("SECONDARY_PROGRAM_STATE").unwrap());
+  let ticket_metadata = pda::ticket_metadata(&ticket_nft_state, &pubkey(&fixture.sell_listing_ticket_nft)).0;
+  let sell_listing = secondary_market::pda::sell_listing(&secondary_market_state, &fixture.event_id, &ticket_metadata).0;
+  let accounts_dir = fixtures_dir().join("accounts");
+
+  [
+    pubkey(&env::var("TICKET_SALE_STATE").unwrap()),
+    ticket_nft_state,
+    secondary_market_state,
+    pubkey(&fixture.sale_account),
+    sell_listing,
+  ]
+  .into_iter()
+  .map(|address| {
+    let path = accounts_dir.join(format!("{}.json", address));
+
+    if !path.exists() {
+      return Err(Report::msg(format!("Fixture account {} is missing. See tests/fixtures/dump_accounts.sh", path.display())))
+    }
+
+    Ok((address, path))
+  })
+  .collect()
+}
+
+fn start_validator(fixture: &Fixture) -> Result<Validator> {
+  let programs = programs()?;
+  let accounts = fixture_accounts(fixture)?;
+  let test_dir = env::temp_dir().join(format!("fiat-checkout-manager-{}", std::process::id()));
+  let ledger_dir = test_dir.join("ledger");
+  fs::create_dir_all(&test_dir)?;
+
+  let mut cmd = Command::new("solana-test-validator");
+  cmd.arg("--reset").arg("--quiet").arg("--ledger").arg(&ledger_dir);
+
+  for (program_id, path) in programs {
+    cmd.arg("--bpf-program").arg(program_id.to_string()).arg(path);
+  }
+
+  for (address, path) in accounts {
+    cmd.arg("--account").arg(address.to_string()).arg(path);
+  }
+
+  // The program only checks whether the ticket nft account exists so its content doesn't matter
+  let minted_ticket_nft = fixture.ticket_nft(MINTED_SEAT_INDEX);
+  let minted_account = write_account(&test_dir, &minted_ticket_nft, &ticket_nft::program_id(), &[])?;
+  cmd.arg("--account").arg(minted_ticket_nft.to_string()).arg(minted_account);
+
+  let child = cmd.stdout(Stdio::null()).stderr(Stdio::null()).spawn()?;
+
+  Ok(Validator(child))
+}
+
+async fn wait_for_validator(rpc_client: &RpcClient) -> Result<()> {
+  for _ in 0..60 {
+    if rpc_client.get_health().await.is_ok() {
+      return Ok(())
+    }
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+  }
+
+  Err(Report::msg("solana-test-validator did not start"))
+}
+
+async fn airdrop(rpc_client: &RpcClient, recipient: &Pubkey, lamports: u64) -> Result<()> {
+  let signature = rpc_client.request_airdrop(recipient, lamports).await?;
+
+  while !rpc_client.confirm_transaction(&signature).await? {
+    tokio::time::sleep(Duration::from_millis(500)).await;
+  }
+
+  Ok(())
+}
+
+/// Starts Redis, Postgres and RabbitMQ unless they are already running. The containers are left running
+/// for the next test run; stop them with `docker compose -f tests/docker-compose.yml down`.
+fn start_services() {
+  if env::var("TEST_SERVICES_RUNNING").is_ok() {
+    return
+  }
+
+  let status = Command::new("docker")
+  .args(["compose", "-f", "tests/docker-compose.yml", "up", "-d", "--wait"])
+  .stdout(Stdio::null())
+  .status()
+  .expect("docker is required to run the integration tests");
+
+  assert!(status.success(), "could not start the test services");
+}
+
+fn psql(args: &[&str]) -> Result<String> {
+  let output = Command::new("psql")
+  .arg(env::var("POSTGRES_URI").unwrap())
+  .args(["-v", "ON_ERROR_STOP=1", "-q", "-t", "-A"])
+  .args(args)
+  .output()?;
+
+  if !output.status.success() {
+    return Err(Report::msg(String::from_utf8_lossy(&output.stderr).to_string()))
+  }
+
+  Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// The migrations that ship with the ticketland-data version in Cargo.lock
+fn ticketland_data_migrations() -> Result<Vec<PathBuf>> {
+  let output = Command::new(env!("CARGO"))
+  .args(["metadata", "--format-version", "1"])
+  .output()?;
+  let metadata: Value = serde_json::from_slice(&output.stdout)?;
+
+  let manifest_path = metadata["packages"].as_array()
+  .and_then(|packages| packages.iter().find(|package| package["name"] == "ticketland-data"))
+  .and_then(|package| package["manifest_path"].as_str())
+  .ok_or(Report::msg("ticketland-data is not a dependency"))?;
+  let migrations_dir = Path::new(manifest_path).with_file_name("migrations");
+
+  // Diesel names the migrations after their timestamp so sorting them gives the order they were written in
+  let mut migrations = fs::read_dir(&migrations_dir)?
+  .filter_map(|entry| Some(entry.ok()?.path().join("up.sql")))
+  .filter(|path| path.exists())
+  .collect::<Vec<_>>();
+  migrations.sort();
+
+  if migrations.is_empty() {
+    return Err(Report::msg(format!("No migrations in {}", migrations_dir.display())))
+  }
+
+  Ok(migrations)
+}
+
+/// Creates the ticketland-data schema on a fresh database and loads the fixture rows. The tables of this service
+/// are created by `Store::new`.
+fn seed_postgres() -> Result<()> {
+  if psql(&["-c", "SELECT to_regclass('public.accounts') IS NULL"])? == "t" {
+    for migration in ticketland_data_migrations()? {
+      psql(&["-f", &migration.to_string_lossy()])?;
+    }
+  }
+
+  psql(&["-f", &fixtures_dir().join("seed.sql").to_string_lossy()])?;
+
+  Ok(())
+}
+
+pub async fn setup() -> Result<TestContext> {
+  dotenv::from_filename(".env.test").ok();
+  START_SERVICES.call_once(start_services);
+
+  let fixture = Fixture::new();
+  let validator = start_validator(&fixture)?;
+  let rpc_client = RpcClient::new(RPC_ENDPOINT.to_string());
+  wait_for_validator(&rpc_client).await?;
+
+  for priv_key in env::var("OPERATOR_PRIV_KEYS").or(env::var("OPERATOR_PRIV_KEY"))?.split(',') {
+    let operator = Keypair::from_base58_string(priv_key.trim());
+    airdrop(&rpc_client, &operator.pubkey(), 10 * LAMPORTS_PER_SOL).await?;
+  }
+
+  seed_postgres()?;
+
+  env::set_var("RPC_ENDPOINT", RPC_ENDPOINT);
+  env::set_var("STRIPE_API_URL", fake_stripe::start().await?);
+
+  let store = Arc::new(Store::new().await);
+  store.tx_sender.refresh_balances().await?;
+
+  let mut redis = store.redis_pool.connection().await?;
+  redis.set_ex(&get_price_key("solana"), &SOL_PRICE, 60_000).await?;
+
+  // Holds live in Redis so they outlive the validator of the previous test
+  let held_tickets = (0..HELD_SEATS).map(|seat_index| fixture.ticket_nft(seat_index).to_string()).collect::<Vec<_>>();
+  release_tickets(&store, &fixture.event_id, &held_tickets.iter().map(String::as_str).collect::<Vec<_>>()).await;
+
+  Ok(TestContext {
+    _validator: validator,
+    fixture,
+    handler: CreatePaymentHandler::new(Arc::clone(&store)),
+    store,
+    rpc_client,
+  })
+}
+
+/// Waits until the validator has moved past the given number of slots i.e. for a reservation to expire
+pub async fn wait_slots(rpc_client: &RpcClient, slots: u64) -> Result<()> {
+  let target = rpc_client.get_slot().await? + slots;
+
+  while rpc_client.get_slot().await? <= target {
+    tokio::time::sleep(Duration::from_millis(200)).await;
+  }
+
+  Ok(())
+}
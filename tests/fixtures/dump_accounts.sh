@@ -0,0 +1,14 @@
+#!/usr/bin/env bash
+# Writes the program state accounts the integration tests load into accounts/. Run it against a cluster where the
+# fixture event of .env.test was created, e.g. `./tests/fixtures/dump_accounts.sh http://127.0.0.1:8899 <sell listing>`.
+# The sell listing address is derived from the fixture ticket; the tests print it when its account is missing.
+set -euo pipefail
+
+url=$1
+sell_listing=$2
+dir=$(dirname "$0")/accounts
+source "$(dirname "$0")/../../.env.test"
+
+for pubkey in "$TICKET_SALE_STATE" "$TICKET_NFT_STATE" "$SECONDARY_PROGRAM_STATE" "$TEST_SALE_ACCOUNT" "$sell_listing"; do
+  solana account "$pubkey" --url "$url" --output json -o "$dir/$pubkey.json"
+done
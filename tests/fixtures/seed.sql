@@ -0,0 +1,34 @@
+-- Fixture data of the integration tests. It is applied on top of the ticketland-data schema and matches the
+-- values in .env.test. Re-running it is a no-op.
+
+INSERT INTO accounts (uid, email, pubkey)
+VALUES ('test_buyer', 'buyer@ticketland.test', '9uT8uuhWTE57xcdLb8tLYBSryA58Ex7FP6GWBGCt6vuM')
+ON CONFLICT DO NOTHING;
+
+INSERT INTO accounts (uid, email, pubkey)
+VALUES ('test_other_buyer', 'other@ticketland.test', 'FRJ6WH76QSPK6xBEizEAuixuc3CQQWK98eoHT3c68qEi')
+ON CONFLICT DO NOTHING;
+
+INSERT INTO events (event_id, account_id, name, location, venue, event_type, start_date, end_date, category)
+VALUES ('test_event', 'test_other_buyer', 'Test event', 'Test', 'Test', 1, NOW(), NOW() + INTERVAL '1 day', 1)
+ON CONFLICT DO NOTHING;
+
+-- Fixed price sale of 100 seats at 1 USD
+INSERT INTO sales (account, event_id, ticket_type_index, ticket_type_name, n_tickets, sale_start_ts, sale_end_ts, sale_type, seat_range_start, seat_range_end)
+VALUES (
+  'GXh3xRYG25FVXdjMUjXeJHcL3eEh6w98TQizGq8CDXef',
+  'test_event',
+  0,
+  'General admission',
+  100,
+  NOW() - INTERVAL '1 day',
+  NOW() + INTERVAL '1 day',
+  '{"FixedPrice": {"price": 100}}',
+  0,
+  100
+)
+ON CONFLICT DO NOTHING;
+
+INSERT INTO sell_listings (sol_account, account_id, ticket_nft, event_id, ask_price, is_open)
+VALUES ('E9T413gcAMjZoTt2brfxUXnhymfVyMGcvp8gYbj8ffsu', 'test_other_buyer', 'HargUm5BZtXaT4cpWTFAuuAUMCY2k9BCp6BmjwHoaoR3', 'test_event', 100, true)
+ON CONFLICT DO NOTHING;
@@ -0,0 +1,33 @@
+mod common;
+
+use eyre::Result;
+use fiat_checkout_manager::utils::error::CheckoutError;
+use common::setup;
+
+/// Outside of the seat range of the fixture sale
+const INVALID_SEAT_INDEX: u32 = 1000;
+
+fn checkout_error(result: Result<impl std::fmt::Debug>) -> CheckoutError {
+  result.unwrap_err().downcast::<CheckoutError>().unwrap()
+}
+
+// The error codes are derived from the `ErrorCode` enums in program_artifacts. These tests make sure they match
+// the codes the deployed programs actually return. `SeatReserved` and `SellListingReserved` are covered by the
+// reservation tests.
+
+#[actix_rt::test]
+async fn maps_invalid_seat_index() -> Result<()> {
+  let ctx = setup().await?;
+  let fixture = &ctx.fixture;
+
+  let result = ctx.handler.reserve_seat(
+    &fixture.sale_account,
+    &fixture.event_id,
+    &fixture.recipient,
+    INVALID_SEAT_INDEX,
+    INVALID_SEAT_INDEX.to_string(),
+  ).await;
+  assert!(matches!(checkout_error(result), CheckoutError::TicketUnavailable));
+
+  Ok(())
+}
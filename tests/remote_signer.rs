@@ -0,0 +1,67 @@
+#[path = "common/local_signer.rs"]
+mod local_signer;
+
+use solana_sdk::{
+  hash::hash,
+  instruction::Instruction,
+  message::Message,
+  signature::{Keypair, Signer},
+  system_instruction,
+};
+use program_artifacts::ticket_sale;
+use fiat_checkout_manager::{
+  services::signer::{OperatorSigner, RemoteSigner},
+  utils::config::RemoteSignerConfig,
+};
+
+const TOKEN: &str = "test_token";
+
+fn config(url: String, token: &str) -> RemoteSignerConfig {
+  RemoteSignerConfig {
+    url,
+    token: token.to_string(),
+  }
+}
+
+fn reserve_seat_ix() -> Instruction {
+  let discriminator = &hash(b"global:reserve_seat").to_bytes()[..8];
+  Instruction::new_with_bytes(ticket_sale::program_id(), discriminator, vec![])
+}
+
+#[actix_rt::test]
+async fn signs_allowed_instructions() {
+  let operator = Keypair::new();
+  let pubkey = operator.pubkey();
+  let url = local_signer::start(vec![operator], TOKEN).await.unwrap();
+  let signer = RemoteSigner::new(config(url, TOKEN), pubkey);
+
+  let message = Message::new(&[reserve_seat_ix()], Some(&pubkey));
+  let signature = signer.sign_message(&message.serialize()).await.unwrap();
+
+  assert!(signature.verify(pubkey.as_ref(), &message.serialize()));
+}
+
+#[actix_rt::test]
+async fn rejects_instructions_outside_of_the_signing_policy() {
+  let operator = Keypair::new();
+  let pubkey = operator.pubkey();
+  let url = local_signer::start(vec![operator], TOKEN).await.unwrap();
+  let signer = RemoteSigner::new(config(url, TOKEN), pubkey);
+
+  let transfer = system_instruction::transfer(&pubkey, &Keypair::new().pubkey(), 1);
+  let message = Message::new(&[reserve_seat_ix(), transfer], Some(&pubkey));
+
+  assert!(signer.sign_message(&message.serialize()).await.is_err());
+}
+
+#[actix_rt::test]
+async fn rejects_requests_with_an_invalid_token() {
+  let operator = Keypair::new();
+  let pubkey = operator.pubkey();
+  let url = local_signer::start(vec![operator], TOKEN).await.unwrap();
+  let signer = RemoteSigner::new(config(url, "wrong_token"), pubkey);
+
+  let message = Message::new(&[reserve_seat_ix()], Some(&pubkey));
+
+  assert!(signer.sign_message(&message.serialize()).await.is_err());
+}
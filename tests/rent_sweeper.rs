@@ -0,0 +1,48 @@
+mod common;
+
+use eyre::Result;
+use solana_sdk::pubkey::Pubkey;
+use program_artifacts::{ticket_sale, secondary_market};
+use fiat_checkout_manager::{
+  jobs::rent_sweeper::{expired_seat_reservations, expired_sell_listing_reservations},
+  services::ticket_purchase::sell_listing_account,
+};
+use common::{setup, wait_slots};
+
+fn seat_reservation(sale_account: &str, seat_index: u32) -> Pubkey {
+  let sale = sale_account.parse::<Pubkey>().unwrap();
+  ticket_sale::pda::seat_reservation(&sale, seat_index, &seat_index.to_string()).0
+}
+
+/// The sweeper filters the accounts by the payer stored at `PAYER_OFFSET`. If the offset doesn't match the layout
+/// of the program accounts the expired reservations are never found.
+#[actix_rt::test]
+async fn finds_expired_reservations_funded_by_the_operator() -> Result<()> {
+  let ctx = setup().await?;
+  let fixture = &ctx.fixture;
+  let operator = ctx.store.tx_sender.operators()[0];
+  let seat_index = 4;
+
+  ctx.handler.release_seat(
+    &fixture.sale_account,
+    &fixture.event_id,
+    &fixture.recipient,
+    seat_index,
+    &seat_index.to_string(),
+  ).await?;
+  ctx.handler.release_sell_listing(&fixture.event_id, &fixture.sell_listing_ticket_nft, &fixture.recipient).await?;
+  wait_slots(&ctx.rpc_client, 2).await?;
+
+  let seat_reservations = expired_seat_reservations(&ctx.rpc_client, &operator).await?;
+  assert!(seat_reservations.iter().any(|(pubkey, _)| *pubkey == seat_reservation(&fixture.sale_account, seat_index)));
+
+  let sell_listing = sell_listing_account(&ctx.store, &fixture.event_id, &fixture.sell_listing_ticket_nft)?;
+  let sell_listing_reservation = secondary_market::pda::sell_listing_reservation(&sell_listing).0;
+  let sell_listing_reservations = expired_sell_listing_reservations(&ctx.rpc_client, &operator).await?;
+  assert!(sell_listing_reservations.iter().any(|(pubkey, _)| *pubkey == sell_listing_reservation));
+
+  // Nothing is found for an operator that didn't fund them
+  assert!(expired_seat_reservations(&ctx.rpc_client, &Pubkey::new_unique()).await?.is_empty());
+
+  Ok(())
+}
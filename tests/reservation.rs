@@ -0,0 +1,178 @@
+mod common;
+
+use std::sync::Arc;
+use eyre::Result;
+use solana_sdk::pubkey::Pubkey;
+use program_artifacts::{
+  ticket_sale::{self, account_data::SeatReservation},
+  secondary_market::{self, account_data::SellListingReservation},
+};
+use fiat_checkout_manager::{
+  models::{
+    create_payment::CreatePayment,
+    codec::Codec,
+  },
+  queue::status_producer::StatusReporter,
+  services::ticket_purchase::{pre_primary_purchase_checks, sell_listing_account, PrePurchaseChecksParams},
+  utils::error::CheckoutError,
+};
+use common::{setup, wait_slots, fake_stripe, MINTED_SEAT_INDEX};
+
+fn seat_reservation(sale_account: &str, seat_index: u32) -> Pubkey {
+  let sale = sale_account.parse::<Pubkey>().unwrap();
+  ticket_sale::pda::seat_reservation(&sale, seat_index, &seat_index.to_string()).0
+}
+
+fn checkout_error(result: Result<impl std::fmt::Debug>) -> CheckoutError {
+  result.unwrap_err().downcast::<CheckoutError>().unwrap()
+}
+
+#[actix_rt::test]
+async fn reserves_free_seat() -> Result<()> {
+  let ctx = setup().await?;
+  let fixture = &ctx.fixture;
+  let seat_index = 1;
+
+  let fee = ctx.handler.reserve_seat(
+    &fixture.sale_account,
+    &fixture.event_id,
+    &fixture.recipient,
+    seat_index,
+    seat_index.to_string(),
+  ).await?;
+  assert!(fee > 0);
+
+  let reservation = ctx.store.rpc_client
+  .get_anchor_account_data::<SeatReservation>(&seat_reservation(&fixture.sale_account, seat_index))
+  .await?;
+  assert_eq!(reservation.recipient.to_string(), fixture.recipient);
+  assert!(reservation.valid_until > ctx.rpc_client.get_slot().await?);
+
+  Ok(())
+}
+
+#[actix_rt::test]
+async fn creates_payment_for_primary_ticket() -> Result<()> {
+  let ctx = setup().await?;
+  let fixture = &ctx.fixture;
+  let mut msg = CreatePayment::Primary {
+    ws_session_id: "ws_session".to_string(),
+    buyer_uid: fixture.buyer_uid.clone(),
+    sale_account: fixture.sale_account.clone(),
+    event_id: fixture.event_id.clone(),
+    ticket_type_index: fixture.ticket_type_index,
+    recipient: fixture.recipient.clone(),
+  };
+
+  let status = StatusReporter::new(&ctx.store.status_producer, "ws_session".to_string(), None, Codec::Borsh);
+
+  let (payment_secret, custodial_wallet) = ctx.handler.checkout(&mut msg, None, &status).await?;
+  assert_eq!(payment_secret, fake_stripe::CLIENT_SECRET);
+  assert_eq!(custodial_wallet, None);
+
+  Ok(())
+}
+
+/// An expired reservation is left as it is. The seat is still free to be minted by the buyer.
+#[actix_rt::test]
+async fn skips_expired_seat_reservation() -> Result<()> {
+  let ctx = setup().await?;
+  let fixture = &ctx.fixture;
+  let seat_index = 2;
+
+  ctx.handler.reserve_seat(
+    &fixture.sale_account,
+    &fixture.event_id,
+    &fixture.other_recipient,
+    seat_index,
+    seat_index.to_string(),
+  ).await?;
+  ctx.handler.release_seat(
+    &fixture.sale_account,
+    &fixture.event_id,
+    &fixture.other_recipient,
+    seat_index,
+    &seat_index.to_string(),
+  ).await?;
+  wait_slots(&ctx.rpc_client, 2).await?;
+
+  let fee = ctx.handler.reserve_seat(
+    &fixture.sale_account,
+    &fixture.event_id,
+    &fixture.recipient,
+    seat_index,
+    seat_index.to_string(),
+  ).await?;
+  assert_eq!(fee, 0);
+
+  let reservation = ctx.store.rpc_client
+  .get_anchor_account_data::<SeatReservation>(&seat_reservation(&fixture.sale_account, seat_index))
+  .await?;
+  assert_eq!(reservation.recipient.to_string(), fixture.other_recipient);
+  assert!(reservation.valid_until < ctx.rpc_client.get_slot().await?);
+
+  Ok(())
+}
+
+#[actix_rt::test]
+async fn rejects_seat_reserved_by_another_buyer() -> Result<()> {
+  let ctx = setup().await?;
+  let fixture = &ctx.fixture;
+  let seat_index = 3;
+
+  ctx.handler.reserve_seat(
+    &fixture.sale_account,
+    &fixture.event_id,
+    &fixture.other_recipient,
+    seat_index,
+    seat_index.to_string(),
+  ).await?;
+
+  let result = ctx.handler.reserve_seat(
+    &fixture.sale_account,
+    &fixture.event_id,
+    &fixture.recipient,
+    seat_index,
+    seat_index.to_string(),
+  ).await;
+  assert!(matches!(checkout_error(result), CheckoutError::TicketReserved));
+
+  Ok(())
+}
+
+#[actix_rt::test]
+async fn rejects_already_minted_ticket() -> Result<()> {
+  let ctx = setup().await?;
+  let fixture = &ctx.fixture;
+
+  let result = pre_primary_purchase_checks(PrePurchaseChecksParams::Primary {
+    store: Arc::clone(&ctx.store),
+    event_id: fixture.event_id.clone(),
+    seat_index: MINTED_SEAT_INDEX,
+    sale_account: fixture.sale_account.clone(),
+    ticket_nft: fixture.ticket_nft(MINTED_SEAT_INDEX).to_string(),
+  }).await;
+  assert_eq!(result.unwrap_err().to_string(), "Ticket unavailable");
+
+  Ok(())
+}
+
+#[actix_rt::test]
+async fn reserves_sell_listing_once() -> Result<()> {
+  let ctx = setup().await?;
+  let fixture = &ctx.fixture;
+  let ticket_nft = &fixture.sell_listing_ticket_nft;
+
+  ctx.handler.reserve_sell_listing(&fixture.event_id, ticket_nft, &fixture.recipient).await?;
+
+  let sell_listing = sell_listing_account(&ctx.store, &fixture.event_id, ticket_nft)?;
+  let reservation = ctx.store.rpc_client
+  .get_anchor_account_data::<SellListingReservation>(&secondary_market::pda::sell_listing_reservation(&sell_listing).0)
+  .await?;
+  assert_eq!(reservation.recipient.to_string(), fixture.recipient);
+
+  let result = ctx.handler.reserve_sell_listing(&fixture.event_id, ticket_nft, &fixture.other_recipient).await;
+  assert!(matches!(checkout_error(result), CheckoutError::TicketReserved));
+
+  Ok(())
+}